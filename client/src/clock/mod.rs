@@ -0,0 +1,80 @@
+use bevy::prelude::*;
+use common::TickRate;
+
+use crate::menu::ClientState;
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputClock>()
+            .add_systems(OnExit(ClientState::InGame), reset_input_clock)
+            .add_systems(
+                Update,
+                drift_fixed_timestep.run_if(in_state(ClientState::InGame)),
+            );
+    }
+}
+
+/// Inputs the server should find waiting when it simulates a tick, enough to ride out jitter.
+const TARGET_INPUT_LEAD: f64 = 2.0;
+/// How much faster the fixed timestep runs per buffered input missing, slower per one in excess.
+const DRIFT_PER_INPUT: f64 = 0.01;
+/// Most the fixed timestep drifts from the server's rate, hard to notice in movement.
+const MAX_DRIFT: f64 = 0.05;
+/// Weight of a new sample in the smoothed lead, one snapshot's worth of buffering is noisy.
+const LEAD_SMOOTHING: f64 = 0.1;
+
+/// How far our inputs run ahead of the server, from the buffer depth it reports in snapshots.
+///
+/// Server time itself is estimated by the [`SnapshotClock`](crate::interpolation::SnapshotClock)
+/// and the round trip by the transport, this only keeps inputs arriving just in time for the
+/// tick that consumes them.
+#[derive(Debug, Default, Resource)]
+pub struct InputClock {
+    latest_tick: Option<u32>,
+    lead: Option<f64>,
+}
+
+impl InputClock {
+    pub fn observe(&mut self, tick: u32, buffered_inputs: u8) {
+        // Snapshots may arrive out of order, an older one reports a stale buffer.
+        if self.latest_tick.is_some_and(|latest| tick <= latest) {
+            return;
+        }
+
+        let sample = buffered_inputs as f64;
+
+        self.latest_tick = Some(tick);
+        self.lead = Some(match self.lead {
+            Some(lead) => lead + (sample - lead) * LEAD_SMOOTHING,
+            None => sample,
+        });
+    }
+
+    /// Smoothed number of our inputs waiting in the server's buffer.
+    pub fn lead(&self) -> Option<f64> {
+        self.lead
+    }
+
+    /// Rate our fixed timestep runs at relative to the server's, above 1 when the buffer runs low.
+    pub fn drift(&self) -> f64 {
+        self.lead.map_or(1.0, |lead| {
+            1.0 + ((TARGET_INPUT_LEAD - lead) * DRIFT_PER_INPUT).clamp(-MAX_DRIFT, MAX_DRIFT)
+        })
+    }
+}
+
+fn reset_input_clock(mut clock: ResMut<InputClock>) {
+    *clock = InputClock::default();
+}
+
+/// Samples inputs slightly faster or slower than the server simulates, so they keep arriving a
+/// little ahead of it.
+fn drift_fixed_timestep(
+    clock: Res<InputClock>,
+    tick_rate: Res<TickRate>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+) {
+    fixed_time.set_timestep_hz(tick_rate.0 * clock.drift());
+}
@@ -0,0 +1,521 @@
+use std::{
+    fs::File,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet2::{
+    netcode::{
+        ClientAuthentication, ConnectToken, NativeSocket, NetcodeClientTransport,
+        NetcodeTransportError,
+    },
+    prelude::{RenetClient, client_just_connected, client_just_disconnected},
+};
+use clap::Parser;
+use common::{
+    DEFAULT_PORT, PROTOCOL_ID, PlayerId,
+    data::Compression,
+    net_sim::{LinkConditions, NetworkConditions, SimulatedSocket},
+};
+
+use crate::{menu::ClientState, sync::DisconnectReason};
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Reconnect>()
+            .init_resource::<ConnectTokens>()
+            .add_message::<Disconnected>()
+            .add_systems(OnEnter(ClientState::Connecting), connect)
+            .add_systems(OnEnter(ClientState::MainMenu), leave)
+            .add_systems(
+                Update,
+                (remember_server, reset_backoff, spend_token).run_if(client_just_connected),
+            )
+            .add_systems(Update, drop_failed_transport)
+            .add_systems(
+                Update,
+                reconnect.run_if(in_state(ClientState::Disconnected)),
+            )
+            // After `Update`, so a reason given by the server this frame is already in place.
+            .add_systems(
+                PostUpdate,
+                handle_disconnect.run_if(client_just_disconnected),
+            );
+    }
+}
+
+/// How many servers are remembered in [`RecentServers`].
+const MAX_RECENT_SERVERS: usize = 10;
+/// Seconds before the first automatic reconnect, doubled after every failed attempt.
+const RECONNECT_BASE_DELAY: f64 = 1.0;
+const RECONNECT_MAX_DELAY: f64 = 30.0;
+
+/// The connection to the server was lost, everything learned from it is stale.
+#[derive(Debug, Message)]
+pub struct Disconnected;
+
+/// Automatic reconnect schedule, attempts back off exponentially.
+#[derive(Debug, Default, Resource)]
+pub struct Reconnect {
+    attempts: u32,
+    /// Elapsed seconds of the next automatic attempt, `None` leaves it to the player.
+    next_attempt_at: Option<f64>,
+    last_error: Option<String>,
+}
+
+impl Reconnect {
+    /// What the player is told below the disconnect reason.
+    pub fn status(&self, now: f64) -> String {
+        match self.next_attempt_at {
+            Some(at) => format!(
+                "Reconnecting in {:.0}s, press R to retry now",
+                (at - now).max(0.0).ceil()
+            ),
+            None => "Press R to reconnect".to_owned(),
+        }
+    }
+
+    /// Counts an attempt made on the player's request, or because one was due.
+    pub fn retry(&mut self) {
+        self.attempts += 1;
+        self.next_attempt_at = None;
+    }
+
+    fn schedule(&mut self, now: f64) {
+        let delay = RECONNECT_BASE_DELAY * 2f64.powi(self.attempts.min(16) as i32);
+
+        self.next_attempt_at = Some(now + delay.min(RECONNECT_MAX_DELAY));
+    }
+}
+
+/// Netcode accepts a connect token from a single address, so once one connected a reconnect needs
+/// a fresh token written to the token file.
+#[derive(Debug, Default, Resource)]
+struct ConnectTokens {
+    /// Used by the connection in progress.
+    current: Option<ConnectToken>,
+    /// Connected once already.
+    spent: Option<ConnectToken>,
+}
+
+#[derive(Debug, Clone, Parser, Resource)]
+#[command(about = "Game client")]
+pub struct ConnectionSettings {
+    /// `host`, `host:port`, `ip`, `ip:port` or `[ipv6]:port`, the port defaults to the game's.
+    /// Falls back to `SERVER_ADDR`, then to the most recent server.
+    pub server: Option<String>,
+    /// Connect token issued for a secured server, replaces the server address.
+    /// Falls back to `CONNECT_TOKEN`.
+    #[arg(long)]
+    pub token: Option<PathBuf>,
+    /// Simulated conditions for packets from the server, e.g. `latency=80,jitter=20,loss=0.02`.
+    #[arg(long)]
+    pub sim_inbound: Option<LinkConditions>,
+    /// Simulated conditions for packets to the server, same format as `--sim-inbound`.
+    #[arg(long)]
+    pub sim_outbound: Option<LinkConditions>,
+    /// Asks the server to send everything uncompressed, to compare bytes on the wire.
+    #[arg(long)]
+    pub no_compression: bool,
+}
+
+impl ConnectionSettings {
+    /// Command-line arguments, with the environment (and `.env`) filling the gaps.
+    pub fn from_args() -> Self {
+        dotenvy::dotenv().ok();
+
+        let mut settings = Self::parse();
+
+        if settings.server.is_none() {
+            settings.server = std::env::var("SERVER_ADDR").ok();
+        }
+        if settings.token.is_none() {
+            settings.token = std::env::var_os("CONNECT_TOKEN").map(PathBuf::from);
+        }
+
+        settings
+    }
+
+    pub fn compression(&self) -> Compression {
+        if self.no_compression {
+            Compression::None
+        } else {
+            Compression::Lz4
+        }
+    }
+
+    pub fn network_conditions(&self) -> NetworkConditions {
+        NetworkConditions {
+            inbound: self.sim_inbound.unwrap_or_default(),
+            outbound: self.sim_outbound.unwrap_or_default(),
+        }
+    }
+}
+
+/// The server we are connected to, as the player typed it.
+#[derive(Debug, Resource)]
+pub struct ServerAddress(pub String);
+
+/// Servers connected to before, most recent first, persisted across runs.
+#[derive(Debug, Default, Resource)]
+pub struct RecentServers {
+    pub servers: Vec<String>,
+}
+
+impl RecentServers {
+    fn path() -> Option<PathBuf> {
+        let config_dir =
+            match std::env::var_os("APPDATA").or_else(|| std::env::var_os("XDG_CONFIG_HOME")) {
+                Some(dir) => PathBuf::from(dir),
+                None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+            };
+
+        Some(config_dir.join("tk").join("recent_servers"))
+    }
+
+    pub fn load() -> Self {
+        let servers = Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self { servers }
+    }
+
+    pub fn remember(&mut self, server: &str) {
+        self.servers.retain(|s| s != server);
+        self.servers.insert(0, server.to_owned());
+        self.servers.truncate(MAX_RECENT_SERVERS);
+
+        let Some(path) = Self::path() else {
+            return;
+        };
+
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, self.servers.join("\n")));
+
+        if let Err(e) = saved {
+            warn!("Could not save recent servers to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn remember_server(server: Option<Res<ServerAddress>>, mut recent: ResMut<RecentServers>) {
+    if let Some(server) = server {
+        recent.remember(&server.0);
+    }
+}
+
+fn reset_backoff(mut reconnect: ResMut<Reconnect>) {
+    *reconnect = Reconnect::default();
+}
+
+fn spend_token(mut tokens: ResMut<ConnectTokens>) {
+    if let Some(token) = tokens.current.take() {
+        tokens.spent = Some(token);
+    }
+}
+
+/// A transport error ends the connection, the reconnect flow takes it from there.
+fn drop_failed_transport(
+    mut errors: MessageReader<NetcodeTransportError>,
+    mut reconnect: ResMut<Reconnect>,
+    client: Option<ResMut<RenetClient>>,
+) {
+    let Some(error) = errors.read().last() else {
+        return;
+    };
+
+    error!("Transport error: {}", error);
+    reconnect.last_error = Some(error.to_string());
+
+    if let Some(mut client) = client {
+        client.disconnect();
+    }
+}
+
+fn handle_disconnect(
+    mut commands: Commands,
+    time: Res<Time>,
+    client: Option<Res<RenetClient>>,
+    reason: Option<Res<DisconnectReason>>,
+    mut reconnect: ResMut<Reconnect>,
+    mut disconnected: MessageWriter<Disconnected>,
+) {
+    // Removed by `leave`, the player asked for it.
+    let Some(client) = client else {
+        return;
+    };
+
+    disconnected.write(Disconnected);
+
+    // Turned away by the server itself, trying again right away would end the same way.
+    if reason.is_some() {
+        reconnect.next_attempt_at = None;
+        return;
+    }
+
+    let cause = reconnect
+        .last_error
+        .take()
+        .or_else(|| client.disconnect_reason().map(|reason| reason.to_string()));
+    let reason = match cause {
+        Some(cause) => format!("Connection lost: {}", cause),
+        None => "Connection lost".to_owned(),
+    };
+
+    warn!("{}", reason);
+    commands.insert_resource(DisconnectReason(reason));
+    reconnect.schedule(time.elapsed_secs_f64());
+}
+
+fn reconnect(
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut reconnect: ResMut<Reconnect>,
+    mut next_state: ResMut<NextState<ClientState>>,
+) {
+    let due = reconnect
+        .next_attempt_at
+        .is_some_and(|at| time.elapsed_secs_f64() >= at);
+
+    if !due && !keys.just_pressed(KeyCode::KeyR) {
+        return;
+    }
+
+    reconnect.retry();
+    next_state.set(ClientState::Connecting);
+}
+
+/// Opens the connection [`ConnectionSettings`] point at, a connect token may hand us a new id.
+#[allow(clippy::too_many_arguments)]
+fn connect(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<ConnectionSettings>,
+    recent: Res<RecentServers>,
+    mut tokens: ResMut<ConnectTokens>,
+    mut reconnect: ResMut<Reconnect>,
+    mut player_id: ResMut<PlayerId>,
+    mut next_state: ResMut<NextState<ClientState>>,
+) {
+    let connection = match renet_init(&settings, &recent, &tokens, Some(player_id.0)) {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("Could not connect: {}", e);
+            commands.insert_resource(DisconnectReason(e));
+
+            // Only keep retrying a server we were already playing on.
+            if reconnect.attempts > 0 {
+                reconnect.schedule(time.elapsed_secs_f64());
+            }
+
+            next_state.set(ClientState::Disconnected);
+            return;
+        }
+    };
+
+    if reconnect.attempts > 0 {
+        info!("Reconnecting, attempt {}", reconnect.attempts);
+    }
+
+    if let Some(server) = connection.server {
+        commands.insert_resource(server);
+    }
+
+    player_id.0 = connection.client_id;
+    tokens.current = connection.token;
+    commands.remove_resource::<DisconnectReason>();
+    commands.insert_resource(connection.client);
+    commands.insert_resource(connection.transport);
+}
+
+/// Closes the connection on the player's request, without the reconnect flow kicking in.
+fn leave(
+    mut commands: Commands,
+    transport: Option<ResMut<NetcodeClientTransport>>,
+    mut reconnect: ResMut<Reconnect>,
+) {
+    if let Some(mut transport) = transport {
+        transport.disconnect();
+    }
+
+    *reconnect = Reconnect::default();
+    commands.remove_resource::<DisconnectReason>();
+    commands.remove_resource::<RenetClient>();
+    commands.remove_resource::<NetcodeClientTransport>();
+}
+
+/// Resolves `host`, `host:port`, `ip`, `ip:port` and `[ipv6]:port`, defaulting to [`DEFAULT_PORT`].
+pub fn resolve_server_addr(server: &str) -> Result<SocketAddr, String> {
+    let server = server.trim();
+
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    // Bare IPv6 addresses are full of colons, so they are told apart before looking for a port.
+    if let Ok(ip) = server.trim_matches(['[', ']']).parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid port in {}", server))?,
+        ),
+        None => (server, DEFAULT_PORT),
+    };
+
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Could not resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", host))
+}
+
+struct Connection {
+    pub client: RenetClient,
+    pub transport: NetcodeClientTransport,
+    pub client_id: u64,
+    pub server: Option<ServerAddress>,
+    pub token: Option<ConnectToken>,
+}
+
+/// Opens a new connection, reusing `client_id` unless a connect token dictates another one.
+fn renet_init(
+    settings: &ConnectionSettings,
+    recent: &RecentServers,
+    tokens: &ConnectTokens,
+    client_id: Option<u64>,
+) -> Result<Connection, String> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| format!("System clock is invalid: {}", e))?;
+
+    let (authentication, client_id, server) =
+        authentication(settings, recent, tokens, client_id, current_time)?;
+    let token = match &authentication {
+        ClientAuthentication::Secure { connect_token } => Some(connect_token.clone()),
+        ClientAuthentication::Unsecure { .. } => None,
+    };
+
+    let server_addr = match &authentication {
+        ClientAuthentication::Secure { connect_token } => connect_token.server_addresses[0],
+        ClientAuthentication::Unsecure { server_addr, .. } => Some(*server_addr),
+    };
+
+    // The local socket has to speak the server's address family.
+    let local_ip: IpAddr = match server_addr {
+        Some(SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    };
+
+    let socket = UdpSocket::bind((local_ip, 0))
+        .map_err(|e| format!("Could not open a local socket: {}", e))?;
+
+    let socket = NativeSocket::new(socket).map_err(|e| e.to_string())?;
+
+    let conditions = settings.network_conditions();
+
+    let transport = if conditions.is_ideal() {
+        NetcodeClientTransport::new(current_time, authentication, socket)
+    } else {
+        warn!(
+            "Simulating network conditions, inbound {}, outbound {}",
+            conditions.inbound, conditions.outbound
+        );
+
+        let socket = SimulatedSocket::new(socket, conditions);
+
+        NetcodeClientTransport::new(current_time, authentication, socket)
+    }
+    .map_err(|e| e.to_string())?;
+
+    let client = RenetClient::new(common::data::renet_config(), transport.is_reliable());
+
+    Ok(Connection {
+        client,
+        transport,
+        client_id,
+        server,
+        token,
+    })
+}
+
+/// Connects with a connect token when given one, otherwise unauthenticated to the server address.
+///
+/// The token file is read again on every attempt, a spent or expired token is refused up front
+/// since the server would silently ignore it.
+fn authentication(
+    settings: &ConnectionSettings,
+    recent: &RecentServers,
+    tokens: &ConnectTokens,
+    client_id: Option<u64>,
+    current_time: Duration,
+) -> Result<(ClientAuthentication, u64, Option<ServerAddress>), String> {
+    if let Some(path) = &settings.token {
+        let connect_token = File::open(path)
+            .map_err(|e| e.to_string())
+            .and_then(|mut file| ConnectToken::read(&mut file).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Invalid connect token {}: {}", path.display(), e))?;
+        let client_id = connect_token.client_id;
+
+        if tokens.spent.as_ref() == Some(&connect_token) {
+            return Err(format!(
+                "Connect token {} was already used, write a fresh one there to reconnect",
+                path.display()
+            ));
+        }
+        if connect_token.expire_timestamp <= current_time.as_secs() {
+            return Err(format!(
+                "Connect token {} expired, write a fresh one there to connect",
+                path.display()
+            ));
+        }
+
+        info!("Connecting with connect token {}", path.display());
+
+        return Ok((
+            ClientAuthentication::Secure { connect_token },
+            client_id,
+            None,
+        ));
+    }
+
+    let server = settings
+        .server
+        .clone()
+        .or_else(|| recent.servers.first().cloned())
+        .ok_or("No server to connect to, pass one as argument or set SERVER_ADDR")?;
+
+    let server_addr = resolve_server_addr(&server)?;
+
+    info!("Connecting to {} ({})", server, server_addr);
+
+    let client_id = client_id.unwrap_or_else(rand::random);
+
+    let authentication = ClientAuthentication::Unsecure {
+        client_id,
+        protocol_id: PROTOCOL_ID,
+        socket_id: 0,
+        server_addr,
+        user_data: None,
+    };
+
+    Ok((authentication, client_id, Some(ServerAddress(server))))
+}
@@ -1,11 +1,11 @@
-use std::f32::consts::FRAC_PI_2;
-
 use bevy::{
     input::mouse::AccumulatedMouseMotion,
     prelude::*,
     window::{CursorGrabMode, CursorOptions, PrimaryWindow},
 };
-use common::{CameraInput, ClientInput, WeaponKind};
+use common::{CameraInput, ClientInput, MAX_CAMERA_PITCH, MAX_CAMERA_ROLL, WeaponKind};
+
+use crate::menu::PauseState;
 
 pub struct Plugin;
 
@@ -14,8 +14,12 @@ impl bevy::prelude::Plugin for Plugin {
         app.init_resource::<ClientInput>()
             .init_resource::<InputSequencing>()
             .init_resource::<CameraSensitivity>()
-            .add_systems(Startup, setup_cursor)
-            .add_systems(Update, (keyboard, mouse, weapon_switch));
+            .add_systems(OnEnter(PauseState::Running), setup_cursor)
+            .add_systems(OnExit(PauseState::Running), (release_cursor, release_input))
+            .add_systems(
+                Update,
+                (keyboard, mouse, weapon_switch).run_if(in_state(PauseState::Running)),
+            );
     }
 }
 
@@ -24,6 +28,24 @@ fn setup_cursor(mut q: Single<&mut CursorOptions, With<PrimaryWindow>>) {
     q.grab_mode = CursorGrabMode::Locked;
 }
 
+fn release_cursor(mut q: Single<&mut CursorOptions, With<PrimaryWindow>>) {
+    q.visible = true;
+    q.grab_mode = CursorGrabMode::None;
+}
+
+/// Lets go of everything held, inputs keep being sent while the menu is open.
+fn release_input(mut input: ResMut<ClientInput>) {
+    input.forward = false;
+    input.left = false;
+    input.backward = false;
+    input.right = false;
+    input.run = false;
+    input.crouch = false;
+    input.jump = false;
+    input.fire = false;
+    input.camera.roll = 0.0;
+}
+
 fn keyboard(
     keyboard: Res<ButtonInput<KeyCode>>,
     mouse: Res<ButtonInput<MouseButton>>,
@@ -56,12 +78,10 @@ fn keyboard(
     input.fire_pressed_sequence = sequencing.fire_pressed_sequence;
     input.reload_sequence = sequencing.reload_sequence;
 
-    const MAX_ROLL_ANGLE: f32 = 0.3;
-
     let left = keyboard.pressed(KeyCode::KeyQ) as i8;
     let right = keyboard.pressed(KeyCode::KeyE) as i8;
 
-    input.camera.roll = (left - right) as f32 * MAX_ROLL_ANGLE;
+    input.camera.roll = (left - right) as f32 * MAX_CAMERA_ROLL;
 }
 
 fn weapon_switch(keyboard: Res<ButtonInput<KeyCode>>, mut input: ResMut<ClientInput>) {
@@ -103,9 +123,7 @@ fn mouse(
     // so the direction picked will for all intents and purposes be arbitrary.
     // Another issue is that for mathematical reasons, the yaw will effectively be flipped when the pitch is at the extremes.
     // To not run into these issues, we clamp the pitch to a safe range.
-    const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01;
-
-    let pitch = (pitch + delta_pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+    let pitch = (pitch + delta_pitch).clamp(-MAX_CAMERA_PITCH, MAX_CAMERA_PITCH);
 
     input.camera = CameraInput { yaw, pitch, roll };
 }
@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use common::{ClientInput, TickRate};
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SnapshotClock>()
+            .add_systems(Update, (interpolate_remote_entities, stamp_view_tick));
+    }
+}
+
+/// How far in the past remote entities are rendered, in seconds.
+const INTERPOLATION_DELAY: f64 = 0.1;
+/// How long an entity keeps moving along its last known velocity once its buffer runs dry.
+const MAX_EXTRAPOLATION: f64 = 0.25;
+const MAX_BUFFERED_SAMPLES: usize = 32;
+/// Clock offsets further off than this are snapped to instead of smoothed (e.g. after a stall).
+const CLOCK_SNAP_THRESHOLD: f64 = 0.25;
+const CLOCK_SMOOTHING: f64 = 0.05;
+
+/// Maps server ticks onto the local clock.
+#[derive(Debug, Default, Resource)]
+pub struct SnapshotClock {
+    offset: Option<f64>,
+}
+
+impl SnapshotClock {
+    pub fn observe(&mut self, tick: u32, now: f64, tick_rate: &TickRate) {
+        let sample = tick as f64 / tick_rate.0 - now;
+
+        self.offset = Some(match self.offset {
+            Some(offset) if (sample - offset).abs() < CLOCK_SNAP_THRESHOLD => {
+                offset + (sample - offset) * CLOCK_SMOOTHING
+            }
+            _ => sample,
+        });
+    }
+
+    /// Fractional server tick remote entities should be displayed at.
+    pub fn render_tick(&self, now: f64, tick_rate: &TickRate) -> Option<f64> {
+        self.offset
+            .map(|offset| (now + offset - INTERPOLATION_DELAY) * tick_rate.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub tick: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+}
+
+/// Time-ordered server states of a remote entity.
+#[derive(Debug, Default, Component)]
+pub struct InterpolationBuffer {
+    samples: VecDeque<Sample>,
+}
+
+impl InterpolationBuffer {
+    pub fn push(&mut self, sample: Sample) {
+        // Snapshots travel unreliably and may arrive out of order or duplicated.
+        let index = self.samples.partition_point(|s| s.tick < sample.tick);
+
+        if self
+            .samples
+            .get(index)
+            .is_some_and(|s| s.tick == sample.tick)
+        {
+            return;
+        }
+
+        self.samples.insert(index, sample);
+
+        if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn latest_tick(&self) -> Option<u32> {
+        self.samples.back().map(|s| s.tick)
+    }
+
+    fn prune(&mut self, render_tick: f64) {
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|s| s.tick as f64 <= render_tick)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    fn sample_at(&self, render_tick: f64, tick_rate: &TickRate) -> Option<(Vec3, Quat)> {
+        let first = self.samples.front()?;
+
+        if render_tick <= first.tick as f64 {
+            return Some((first.translation, first.rotation));
+        }
+
+        let next = self
+            .samples
+            .partition_point(|s| s.tick as f64 <= render_tick);
+
+        if let Some(to) = self.samples.get(next) {
+            let from = &self.samples[next - 1];
+            let t = ((render_tick - from.tick as f64) / (to.tick - from.tick) as f64) as f32;
+
+            return Some((
+                from.translation.lerp(to.translation, t),
+                from.rotation.slerp(to.rotation, t),
+            ));
+        }
+
+        let last = self.samples.back()?;
+        let ahead = ((render_tick - last.tick as f64) / tick_rate.0).min(MAX_EXTRAPOLATION) as f32;
+
+        Some((last.translation + last.velocity * ahead, last.rotation))
+    }
+}
+
+fn interpolate_remote_entities(
+    time: Res<Time>,
+    clock: Res<SnapshotClock>,
+    tick_rate: Res<TickRate>,
+    mut query: Query<(&mut InterpolationBuffer, &mut Transform)>,
+) {
+    let Some(render_tick) = clock.render_tick(time.elapsed_secs_f64(), &tick_rate) else {
+        return;
+    };
+
+    for (mut buffer, mut transform) in query.iter_mut() {
+        buffer.prune(render_tick);
+
+        if let Some((translation, rotation)) = buffer.sample_at(render_tick, &tick_rate) {
+            transform.translation = translation;
+            transform.rotation = rotation;
+        }
+    }
+}
+
+/// Tells the server which tick we are looking at, so our shots can be lag compensated.
+fn stamp_view_tick(
+    time: Res<Time>,
+    clock: Res<SnapshotClock>,
+    tick_rate: Res<TickRate>,
+    mut input: ResMut<ClientInput>,
+) {
+    if let Some(render_tick) = clock.render_tick(time.elapsed_secs_f64(), &tick_rate) {
+        input.view_tick = Some(render_tick.round().max(0.0) as u32);
+    }
+}
@@ -0,0 +1,392 @@
+use bevy::{
+    color::palettes::tailwind,
+    input::{ButtonState, keyboard::KeyboardInput},
+    prelude::*,
+};
+use bevy_renet2::prelude::client_connected;
+
+use crate::{
+    connection::{ConnectionSettings, Disconnected, RecentServers, Reconnect},
+    sync::DisconnectReason,
+};
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_sub_state::<PauseState>()
+            .add_systems(OnEnter(ClientState::MainMenu), spawn_main_menu)
+            .add_systems(OnEnter(ClientState::Connecting), spawn_connecting_screen)
+            .add_systems(
+                OnEnter(ClientState::Disconnected),
+                spawn_disconnected_screen,
+            )
+            .add_systems(OnEnter(PauseState::Paused), spawn_pause_menu)
+            .add_systems(
+                Update,
+                (
+                    type_server_address.run_if(in_state(ClientState::MainMenu)),
+                    enter_game.run_if(in_state(ClientState::Connecting).and(client_connected)),
+                    sync_disconnected_status.run_if(in_state(ClientState::Disconnected)),
+                    toggle_pause.run_if(in_state(ClientState::InGame)),
+                    leave_menu.run_if(not(in_state(ClientState::InGame))),
+                    show_disconnected.run_if(on_message::<Disconnected>),
+                    (press_buttons, highlight_buttons),
+                ),
+            );
+    }
+}
+
+/// Where the client is, from the main menu to playing on a server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, States)]
+pub enum ClientState {
+    #[default]
+    MainMenu,
+    Connecting,
+    InGame,
+    /// The connection ended without the player asking for it.
+    Disconnected,
+}
+
+/// Only exists in game, the world keeps running on the server while paused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, SubStates)]
+#[source(ClientState = ClientState::InGame)]
+pub enum PauseState {
+    #[default]
+    Running,
+    Paused,
+}
+
+#[derive(Debug, Clone, Component)]
+enum MenuButton {
+    /// Connects to the address typed in the main menu.
+    Connect,
+    ConnectTo(String),
+    Reconnect,
+    MainMenu,
+    Resume,
+    Quit,
+}
+
+#[derive(Debug, Component)]
+struct ServerAddressField;
+
+#[derive(Debug, Component)]
+struct DisconnectedStatus;
+
+/// Longest server address the main menu accepts.
+const MAX_SERVER_ADDRESS_LEN: usize = 255;
+
+const BUTTON_COLOR: Color = Color::srgb(0.15, 0.15, 0.15);
+const BUTTON_HOVERED_COLOR: Color = Color::srgb(0.25, 0.25, 0.25);
+const BUTTON_PRESSED_COLOR: Color = Color::srgb(0.35, 0.75, 0.35);
+
+fn screen(state: impl States) -> impl Bundle {
+    (
+        DespawnOnExit(state),
+        Node {
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(12.0),
+            ..default()
+        },
+        GlobalZIndex(300),
+    )
+}
+
+fn label(text: impl Into<String>, font_size: f32, color: Color) -> impl Bundle {
+    (
+        Text::new(text),
+        TextFont::from_font_size(font_size),
+        TextColor(color),
+    )
+}
+
+fn button(text: impl Into<String>, action: MenuButton) -> impl Bundle {
+    (
+        Button,
+        action,
+        Node {
+            width: Val::Px(320.0),
+            padding: UiRect::all(Val::Px(10.0)),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        BackgroundColor(BUTTON_COLOR),
+        children![label(text, 22.0, Color::WHITE)],
+    )
+}
+
+fn spawn_main_menu(
+    mut commands: Commands,
+    settings: Res<ConnectionSettings>,
+    recent: Res<RecentServers>,
+) {
+    commands.spawn((Camera2d, DespawnOnExit(ClientState::MainMenu)));
+
+    let address = settings
+        .server
+        .clone()
+        .or_else(|| recent.servers.first().cloned())
+        .unwrap_or_default();
+
+    commands
+        .spawn(screen(ClientState::MainMenu))
+        .with_children(|menu| {
+            menu.spawn(label("tk", 64.0, Color::WHITE));
+
+            match &settings.token {
+                Some(token) => {
+                    menu.spawn(label(
+                        format!("Using connect token {}", token.display()),
+                        18.0,
+                        Color::from(tailwind::GRAY_400),
+                    ));
+                }
+                None => {
+                    menu.spawn(label(
+                        "Server address",
+                        18.0,
+                        Color::from(tailwind::GRAY_400),
+                    ));
+                    menu.spawn((
+                        Node {
+                            width: Val::Px(320.0),
+                            min_height: Val::Px(40.0),
+                            padding: UiRect::all(Val::Px(8.0)),
+                            ..default()
+                        },
+                        BackgroundColor(Color::srgb(0.08, 0.08, 0.08)),
+                        children![(ServerAddressField, label(address, 22.0, Color::WHITE))],
+                    ));
+                }
+            }
+
+            menu.spawn(button("Connect", MenuButton::Connect));
+
+            if settings.token.is_none() && !recent.servers.is_empty() {
+                menu.spawn(label(
+                    "Recent servers",
+                    18.0,
+                    Color::from(tailwind::GRAY_400),
+                ));
+
+                for server in &recent.servers {
+                    menu.spawn(button(
+                        server.clone(),
+                        MenuButton::ConnectTo(server.clone()),
+                    ));
+                }
+            }
+
+            menu.spawn(button("Quit", MenuButton::Quit));
+        });
+}
+
+fn spawn_connecting_screen(mut commands: Commands, settings: Res<ConnectionSettings>) {
+    commands.spawn((Camera2d, DespawnOnExit(ClientState::Connecting)));
+
+    let target = match (&settings.token, &settings.server) {
+        (Some(_), _) => "Connecting with connect token".to_owned(),
+        (None, Some(server)) => format!("Connecting to {}", server),
+        (None, None) => "Connecting".to_owned(),
+    };
+
+    commands.spawn((
+        screen(ClientState::Connecting),
+        children![
+            label(target, 28.0, Color::WHITE),
+            button("Cancel", MenuButton::MainMenu),
+        ],
+    ));
+}
+
+fn spawn_disconnected_screen(mut commands: Commands, reason: Option<Res<DisconnectReason>>) {
+    commands.spawn((Camera2d, DespawnOnExit(ClientState::Disconnected)));
+
+    let reason = reason.map_or_else(|| "Disconnected".to_owned(), |reason| reason.0.clone());
+
+    commands.spawn((
+        screen(ClientState::Disconnected),
+        children![
+            label(reason, 28.0, Color::from(tailwind::RED_400)),
+            (
+                DisconnectedStatus,
+                label("", 18.0, Color::from(tailwind::GRAY_400))
+            ),
+            button("Reconnect", MenuButton::Reconnect),
+            button("Main menu", MenuButton::MainMenu),
+        ],
+    ));
+}
+
+fn spawn_pause_menu(mut commands: Commands) {
+    commands.spawn((
+        screen(PauseState::Paused),
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        children![
+            label("Paused", 48.0, Color::WHITE),
+            button("Resume", MenuButton::Resume),
+            button("Disconnect", MenuButton::MainMenu),
+            button("Quit", MenuButton::Quit),
+        ],
+    ));
+}
+
+/// Starts connecting to `server`, remembered as the server the reconnect flow goes back to.
+fn connect_to(
+    server: &str,
+    settings: &mut ConnectionSettings,
+    next_state: &mut NextState<ClientState>,
+) {
+    let server = server.trim();
+
+    if !server.is_empty() {
+        settings.server = Some(server.to_owned());
+    }
+
+    next_state.set(ClientState::Connecting);
+}
+
+fn type_server_address(
+    mut keys: MessageReader<KeyboardInput>,
+    mut settings: ResMut<ConnectionSettings>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut field: Query<&mut Text, With<ServerAddressField>>,
+) {
+    let Ok(mut address) = field.single_mut() else {
+        return;
+    };
+
+    for key in keys.read() {
+        if key.state != ButtonState::Pressed {
+            continue;
+        }
+
+        match key.key_code {
+            KeyCode::Enter | KeyCode::NumpadEnter => {
+                connect_to(&address.0, &mut settings, &mut next_state);
+            }
+            KeyCode::Backspace => {
+                address.0.pop();
+            }
+            _ => {
+                let Some(text) = &key.text else {
+                    continue;
+                };
+
+                let printable = text.chars().filter(|c| !c.is_control());
+
+                for c in printable {
+                    if address.0.len() < MAX_SERVER_ADDRESS_LEN {
+                        address.0.push(c);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn enter_game(mut next_state: ResMut<NextState<ClientState>>) {
+    next_state.set(ClientState::InGame);
+}
+
+fn show_disconnected(
+    state: Res<State<ClientState>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+) {
+    if matches!(state.get(), ClientState::Connecting | ClientState::InGame) {
+        next_state.set(ClientState::Disconnected);
+    }
+}
+
+fn sync_disconnected_status(
+    time: Res<Time>,
+    reconnect: Res<Reconnect>,
+    mut status: Single<&mut Text, With<DisconnectedStatus>>,
+) {
+    let text = reconnect.status(time.elapsed_secs_f64());
+
+    if status.0 != text {
+        status.0 = text;
+    }
+}
+
+fn toggle_pause(
+    keys: Res<ButtonInput<KeyCode>>,
+    state: Res<State<PauseState>>,
+    mut next_state: ResMut<NextState<PauseState>>,
+) {
+    if !keys.just_pressed(KeyCode::Escape) {
+        return;
+    }
+
+    next_state.set(match state.get() {
+        PauseState::Running => PauseState::Paused,
+        PauseState::Paused => PauseState::Running,
+    });
+}
+
+/// Escape backs out of the connecting and disconnected screens.
+fn leave_menu(
+    keys: Res<ButtonInput<KeyCode>>,
+    state: Res<State<ClientState>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+) {
+    if keys.just_pressed(KeyCode::Escape) && *state.get() != ClientState::MainMenu {
+        next_state.set(ClientState::MainMenu);
+    }
+}
+
+fn press_buttons(
+    buttons: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
+    mut settings: ResMut<ConnectionSettings>,
+    mut reconnect: ResMut<Reconnect>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut next_pause_state: ResMut<NextState<PauseState>>,
+    mut exit: MessageWriter<AppExit>,
+    field: Query<&Text, With<ServerAddressField>>,
+) {
+    for (interaction, action) in buttons.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+
+        match action {
+            MenuButton::Connect => {
+                let address = field
+                    .single()
+                    .map(|text| text.0.clone())
+                    .unwrap_or_default();
+
+                connect_to(&address, &mut settings, &mut next_state);
+            }
+            MenuButton::ConnectTo(server) => connect_to(server, &mut settings, &mut next_state),
+            MenuButton::Reconnect => {
+                reconnect.retry();
+                next_state.set(ClientState::Connecting);
+            }
+            MenuButton::MainMenu => next_state.set(ClientState::MainMenu),
+            MenuButton::Resume => next_pause_state.set(PauseState::Running),
+            MenuButton::Quit => {
+                exit.write(AppExit::Success);
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn highlight_buttons(
+    mut buttons: Query<(&Interaction, &mut BackgroundColor), (Changed<Interaction>, With<Button>)>,
+) {
+    for (interaction, mut color) in buttons.iter_mut() {
+        color.0 = match interaction {
+            Interaction::Pressed => BUTTON_PRESSED_COLOR,
+            Interaction::Hovered => BUTTON_HOVERED_COLOR,
+            Interaction::None => BUTTON_COLOR,
+        };
+    }
+}
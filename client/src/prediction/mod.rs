@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use common::{
+    ClientInput, MovementState, PlayerId, PlayerVisualState, TICK_RATE, TickRate,
+    movement::{player_collider, player_collision_groups, step_player_movement},
+};
+
+use crate::menu::ClientState;
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Time::<Fixed>::from_hz(TICK_RATE))
+            .init_resource::<InputHistory>()
+            .add_message::<AuthoritativeMovement>()
+            .add_systems(OnExit(ClientState::InGame), reset_input_history)
+            .add_systems(
+                Update,
+                (setup_local_player, reconcile_local_player).run_if(in_state(ClientState::InGame)),
+            )
+            .add_systems(
+                FixedUpdate,
+                predict_local_player.run_if(in_state(ClientState::InGame)),
+            );
+    }
+}
+
+// Two seconds worth of ticks; anything older than this will never be acknowledged.
+const MAX_BUFFERED_INPUTS: usize = 256;
+
+/// Inputs that were applied locally but not yet acknowledged by the server.
+#[derive(Debug, Default, Resource)]
+pub struct InputHistory {
+    last_sequence: u32,
+    acknowledged_sequence: u32,
+    inputs: VecDeque<ClientInput>,
+}
+
+impl InputHistory {
+    /// The last `count` unacknowledged inputs, oldest first.
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = &ClientInput> {
+        self.inputs
+            .iter()
+            .skip(self.inputs.len().saturating_sub(count))
+    }
+
+    fn push(&mut self, mut input: ClientInput) -> &ClientInput {
+        self.last_sequence = self.last_sequence.wrapping_add(1);
+        input.sequence = self.last_sequence;
+
+        if self.inputs.len() == MAX_BUFFERED_INPUTS {
+            self.inputs.pop_front();
+        }
+
+        self.inputs.push_back(input);
+        self.inputs.back().expect("Input was just pushed")
+    }
+}
+
+/// Server state of the local player, as of the last input the server processed.
+#[derive(Debug, Message)]
+pub struct AuthoritativeMovement {
+    pub last_input_sequence: u32,
+    pub translation: Vec3,
+    pub velocity: Vec3,
+    pub grounded: bool,
+    pub jump_queued: bool,
+    pub crouched: bool,
+}
+
+type LocalPlayer<'a> = (
+    Entity,
+    &'a PlayerVisualState,
+    &'a mut MovementState,
+    &'a mut Collider,
+    &'a mut Transform,
+);
+
+fn reset_input_history(mut history: ResMut<InputHistory>) {
+    *history = InputHistory::default();
+}
+
+fn setup_local_player(mut commands: Commands, query: Query<Entity, Added<PlayerId>>) {
+    for entity in query.iter() {
+        commands.entity(entity).insert((
+            MovementState {
+                grounded: true,
+                ..default()
+            },
+            player_collider(false),
+            player_collision_groups(),
+        ));
+    }
+}
+
+pub fn predict_local_player(
+    rapier_context: ReadRapierContext,
+    tick_rate: Res<TickRate>,
+    input: Res<ClientInput>,
+    mut history: ResMut<InputHistory>,
+    player: Single<LocalPlayer, With<PlayerId>>,
+) {
+    let rapier_context = rapier_context
+        .single()
+        .expect("Default Rapier context to exist");
+    let (entity, visual_state, mut movement, mut collider, mut transform) = player.into_inner();
+    let input = history.push(input.clone());
+
+    if !visual_state.alive {
+        return;
+    }
+
+    step_player_movement(
+        entity,
+        &rapier_context,
+        input,
+        &mut movement,
+        &mut collider,
+        &mut transform,
+        tick_rate.delta(),
+    );
+}
+
+fn reconcile_local_player(
+    mut messages: MessageReader<AuthoritativeMovement>,
+    rapier_context: ReadRapierContext,
+    tick_rate: Res<TickRate>,
+    mut history: ResMut<InputHistory>,
+    player: Single<LocalPlayer, With<PlayerId>>,
+) {
+    let Some(state) = messages
+        .read()
+        .filter(|state| state.last_input_sequence >= history.acknowledged_sequence)
+        .last()
+    else {
+        return;
+    };
+
+    let rapier_context = rapier_context
+        .single()
+        .expect("Default Rapier context to exist");
+    let (entity, visual_state, mut movement, mut collider, mut transform) = player.into_inner();
+
+    history.acknowledged_sequence = state.last_input_sequence;
+    history
+        .inputs
+        .retain(|input| input.sequence > state.last_input_sequence);
+
+    transform.translation = state.translation;
+    movement.velocity = state.velocity;
+    movement.grounded = state.grounded;
+    movement.jump_queued = state.jump_queued;
+
+    if movement.crouched != state.crouched {
+        movement.crouched = state.crouched;
+        *collider = player_collider(state.crouched);
+    }
+
+    if !visual_state.alive {
+        return;
+    }
+
+    let delta = tick_rate.delta();
+
+    for input in history.inputs.iter() {
+        step_player_movement(
+            entity,
+            &rapier_context,
+            input,
+            &mut movement,
+            &mut collider,
+            &mut transform,
+            delta,
+        );
+    }
+}
@@ -3,8 +3,17 @@ use bevy::{
     prelude::*,
 };
 use common::{
-    Client, ClientInput, Lobby, PlayerId, PlayerVisualState, WeaponKind, PLAYER_CROUCH_SCALE,
-    PLAYER_CROUCH_VIEW_OFFSET,
+    Client, ClientInput, PlayerId, PlayerStatus, PlayerVisualState, WeaponKind,
+    PLAYER_CROUCH_SCALE, PLAYER_CROUCH_VIEW_OFFSET,
+};
+
+use crate::{menu::ClientState, sync::PrivateState};
+
+mod net_stats;
+
+use net_stats::{
+    NetGraphs, NetStatsVisible, sample_net_stats, spawn_net_stats_overlay, sync_net_graphs,
+    toggle_net_stats_overlay,
 };
 
 pub struct Plugin;
@@ -12,26 +21,37 @@ pub struct Plugin;
 impl bevy::prelude::Plugin for Plugin {
     fn build(&self, app: &mut App) {
         let startup_systems = (
-            spawn_view_model,
             spawn_world_model,
             spawn_lights,
             spawn_crosshair,
             spawn_ammo_hud,
+            spawn_health_hud,
+            spawn_respawn_hint,
+            spawn_net_stats_overlay,
         );
 
-        app.add_systems(Startup, startup_systems).add_systems(
-            Update,
-            (
-                change_fov,
-                sync_local_player_rotation,
-                sync_local_view,
-                sync_player_visuals,
-                sync_local_alive_visibility,
-                sync_view_weapon_visibility,
-                sync_ammo_hud,
-                sync_barrel_laser,
-            ),
-        );
+        app.init_resource::<NetStatsVisible>()
+            .init_resource::<NetGraphs>()
+            .add_systems(OnEnter(ClientState::InGame), startup_systems)
+            .add_systems(
+                Update,
+                (
+                    spawn_view_model,
+                    change_fov,
+                    sync_local_player_rotation,
+                    sync_local_view,
+                    sync_player_visuals,
+                    sync_local_alive_visibility,
+                    sync_view_weapon_visibility,
+                    sync_ammo_hud,
+                    sync_health_hud,
+                    sync_respawn_hint,
+                    sync_barrel_laser,
+                    toggle_net_stats_overlay,
+                    (sample_net_stats, sync_net_graphs).chain(),
+                )
+                    .run_if(in_state(ClientState::InGame)),
+            );
     }
 }
 
@@ -55,17 +75,18 @@ pub struct ProjectileVisual {
     pub id: u64,
 }
 
-#[derive(Debug, Component)]
-pub struct ImpactMarkVisual {
-    pub id: u64,
-}
-
 #[derive(Debug, Component)]
 struct Crosshair;
 
 #[derive(Debug, Component)]
 struct AmmoHud;
 
+#[derive(Debug, Component)]
+struct HealthHud;
+
+#[derive(Debug, Component)]
+struct RespawnHint;
+
 #[derive(Debug, Component)]
 struct WeaponViewModel {
     weapon: WeaponKind,
@@ -81,48 +102,43 @@ const DEFAULT_RENDER_LAYER: usize = 0;
 const VIEW_MODEL_RENDER_LAYER: usize = 1;
 const BARREL_LASER_LENGTH: f32 = 25.0;
 
+/// Dresses our own player once the server replicates it.
 fn spawn_view_model(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut lobby: ResMut<Lobby>,
     player_id: Res<PlayerId>,
+    players: Query<(Entity, &Client), Added<Client>>,
 ) {
+    let Some((player, _)) = players.iter().find(|(_, client)| client.id == player_id.0) else {
+        return;
+    };
+
     let arm = meshes.add(Cuboid::new(0.055, 0.055, 0.34));
     let arm_material = materials.add(Color::from(tailwind::TEAL_200));
 
-    let player = commands
-        .spawn((
-            Client { id: player_id.0 },
-            PlayerId(player_id.0),
-            Transform::from_xyz(0.0, 1.0, 0.0),
-            Visibility::default(),
-            children![
-                world_camera(),
-                view_model_camera(),
-                player_right_arm(arm, arm_material),
-                weapon_view_model(
-                    &mut meshes,
-                    &mut materials,
-                    WeaponKind::Rifle,
-                    Visibility::Inherited,
-                ),
-                weapon_view_model(
-                    &mut meshes,
-                    &mut materials,
-                    WeaponKind::Pistol,
-                    Visibility::Hidden,
-                ),
-            ],
-            PlayerVisualState {
-                alive: true,
-                weapon: WeaponKind::Rifle,
-                ..default()
-            },
-        ))
-        .id();
-
-    lobby.players.insert(player_id.0, player);
+    commands.entity(player).insert((
+        PlayerId(player_id.0),
+        Transform::from_xyz(0.0, 1.0, 0.0),
+        Visibility::default(),
+        children![
+            world_camera(),
+            view_model_camera(),
+            player_right_arm(arm, arm_material),
+            weapon_view_model(
+                &mut meshes,
+                &mut materials,
+                WeaponKind::Rifle,
+                Visibility::Inherited,
+            ),
+            weapon_view_model(
+                &mut meshes,
+                &mut materials,
+                WeaponKind::Pistol,
+                Visibility::Hidden,
+            ),
+        ],
+    ));
 }
 
 fn world_camera() -> impl Bundle {
@@ -302,6 +318,7 @@ fn weapon_part(
 fn spawn_crosshair(mut commands: Commands) {
     commands
         .spawn((
+            DespawnOnExit(ClientState::InGame),
             Node {
                 width: Val::Percent(100.0),
                 height: Val::Percent(100.0),
@@ -324,6 +341,7 @@ fn spawn_crosshair(mut commands: Commands) {
 
 fn spawn_ammo_hud(mut commands: Commands) {
     commands.spawn((
+        DespawnOnExit(ClientState::InGame),
         AmmoHud,
         Text::new("30"),
         Node {
@@ -333,11 +351,51 @@ fn spawn_ammo_hud(mut commands: Commands) {
             ..default()
         },
         TextFont::from_font_size(28.0),
+        TextLayout::new_with_justify(Justify::Right),
         TextColor(Color::WHITE),
         GlobalZIndex(100),
     ));
 }
 
+fn spawn_health_hud(mut commands: Commands) {
+    commands.spawn((
+        DespawnOnExit(ClientState::InGame),
+        HealthHud,
+        Text::new(""),
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Px(20.0),
+            bottom: Val::Px(18.0),
+            ..default()
+        },
+        TextFont::from_font_size(28.0),
+        TextColor(Color::WHITE),
+        GlobalZIndex(100),
+    ));
+}
+
+fn spawn_respawn_hint(mut commands: Commands) {
+    commands
+        .spawn((
+            DespawnOnExit(ClientState::InGame),
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            GlobalZIndex(100),
+        ))
+        .with_child((
+            RespawnHint,
+            Text::new("Press Space to respawn"),
+            TextFont::from_font_size(32.0),
+            TextColor(Color::WHITE),
+            Visibility::Hidden,
+        ));
+}
+
 fn spawn_world_model(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -347,15 +405,21 @@ fn spawn_world_model(
     let cube = meshes.add(Cuboid::new(2.0, 0.5, 1.0));
     let material = materials.add(Color::WHITE);
 
-    commands.spawn((Mesh3d(floor), MeshMaterial3d(material.clone())));
+    commands.spawn((
+        DespawnOnExit(ClientState::InGame),
+        Mesh3d(floor),
+        MeshMaterial3d(material.clone()),
+    ));
 
     commands.spawn((
+        DespawnOnExit(ClientState::InGame),
         Mesh3d(cube.clone()),
         MeshMaterial3d(material.clone()),
         Transform::from_xyz(0.0, 0.25, -3.0),
     ));
 
     commands.spawn((
+        DespawnOnExit(ClientState::InGame),
         Mesh3d(cube),
         MeshMaterial3d(material),
         Transform::from_xyz(0.75, 1.75, 0.0),
@@ -364,6 +428,7 @@ fn spawn_world_model(
 
 fn spawn_lights(mut commands: Commands) {
     commands.spawn((
+        DespawnOnExit(ClientState::InGame),
         PointLight {
             color: Color::from(tailwind::ROSE_300),
             shadows_enabled: true,
@@ -374,6 +439,7 @@ fn spawn_lights(mut commands: Commands) {
     ));
 }
 
+#[allow(clippy::type_complexity)]
 fn change_fov(
     input: Res<ButtonInput<KeyCode>>,
     mut projections: ParamSet<(
@@ -469,9 +535,10 @@ fn sync_player_visuals(
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn sync_local_alive_visibility(
     player_state: Single<&PlayerVisualState, With<PlayerId>>,
-    mut overlays: Query<&mut Visibility, Or<(With<Crosshair>, With<AmmoHud>)>>,
+    mut overlays: Query<&mut Visibility, Or<(With<Crosshair>, With<AmmoHud>, With<HealthHud>)>>,
 ) {
     for mut visibility in overlays.iter_mut() {
         *visibility = if player_state.alive {
@@ -506,12 +573,61 @@ fn sync_view_weapon_visibility(
     }
 }
 
+/// Active magazine, or the reload countdown, above what is left in the other weapons.
 fn sync_ammo_hud(
-    player_state: Single<&PlayerVisualState, With<PlayerId>>,
+    player: Single<(&PlayerVisualState, &PlayerStatus), With<PlayerId>>,
+    private: Option<Res<PrivateState>>,
     mut query: Query<&mut Text, With<AmmoHud>>,
 ) {
+    let (player_state, status) = player.into_inner();
+    let reload = private.and_then(|private| private.reload);
+
+    let active = match reload {
+        Some(reload) if reload.weapon == player_state.weapon => {
+            format!("Reloading {:.1}s", reload.remaining)
+        }
+        _ => status.ammo(player_state.weapon).to_string(),
+    };
+    let others = WeaponKind::ALL
+        .into_iter()
+        .filter(|&weapon| weapon != player_state.weapon)
+        .map(|weapon| format!("{} {}", weapon.spec().name, status.ammo(weapon)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let hud = format!("{}\n{}", active, others);
+
+    for mut text in query.iter_mut() {
+        if **text != hud {
+            **text = hud.clone();
+        }
+    }
+}
+
+fn sync_health_hud(
+    status: Single<&PlayerStatus, With<PlayerId>>,
+    mut query: Query<&mut Text, With<HealthHud>>,
+) {
+    let hud = format!("{:.0} HP", status.health.ceil());
+
     for mut text in query.iter_mut() {
-        **text = player_state.ammo_in_mag.to_string();
+        if **text != hud {
+            **text = hud.clone();
+        }
+    }
+}
+
+fn sync_respawn_hint(
+    status: Query<&PlayerStatus, With<PlayerId>>,
+    mut query: Query<&mut Visibility, With<RespawnHint>>,
+) {
+    let can_respawn = status.single().is_ok_and(PlayerStatus::can_respawn);
+
+    for mut visibility in query.iter_mut() {
+        *visibility = if can_respawn {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
     }
 }
 
@@ -0,0 +1,304 @@
+use std::collections::VecDeque;
+
+use bevy::{color::palettes::tailwind, prelude::*};
+use bevy_renet2::prelude::RenetClient;
+
+use crate::{clock::InputClock, menu::ClientState, sync::NetStats};
+
+/// Seconds between two points of a graph.
+const SAMPLE_INTERVAL: f64 = 0.25;
+/// Points per graph, 15 seconds of history.
+const GRAPH_SAMPLES: usize = 60;
+const GRAPH_HEIGHT: f32 = 24.0;
+const BAR_WIDTH: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy)]
+enum Metric {
+    Rtt,
+    PacketLoss,
+    BytesReceived,
+    BytesSent,
+    SnapshotRate,
+    SnapshotSize,
+    SnapshotAge,
+    InputRate,
+    InputLead,
+    ClockDrift,
+    DecodeFailures,
+}
+
+impl Metric {
+    const ALL: [Metric; 11] = [
+        Metric::Rtt,
+        Metric::PacketLoss,
+        Metric::BytesReceived,
+        Metric::BytesSent,
+        Metric::SnapshotRate,
+        Metric::SnapshotSize,
+        Metric::SnapshotAge,
+        Metric::InputRate,
+        Metric::InputLead,
+        Metric::ClockDrift,
+        Metric::DecodeFailures,
+    ];
+
+    fn label(self, value: f32) -> String {
+        match self {
+            Metric::Rtt => format!("RTT {:.0} ms", value),
+            Metric::PacketLoss => format!("Loss {:.1} %", value),
+            Metric::BytesReceived => format!("In {:.1} KB/s", value),
+            Metric::BytesSent => format!("Out {:.1} KB/s", value),
+            Metric::SnapshotRate => format!("Snapshots {:.0}/s", value),
+            Metric::SnapshotSize => format!("Snapshot {:.0} B", value),
+            Metric::SnapshotAge => format!("Snapshot age {:.0} ms", value),
+            Metric::InputRate => format!("Inputs {:.0}/s", value),
+            Metric::InputLead => format!("Input lead {:.1} ticks", value),
+            Metric::ClockDrift => format!("Clock drift {:+.1} %", value),
+            Metric::DecodeFailures => format!("Decode failures {:.0}/s", value),
+        }
+    }
+
+    /// Value a graph is scaled to at least, so small jitter doesn't fill it.
+    fn min_scale(self) -> f32 {
+        match self {
+            Metric::Rtt => 100.0,
+            Metric::PacketLoss => 5.0,
+            Metric::BytesReceived | Metric::BytesSent => 10.0,
+            Metric::SnapshotRate | Metric::InputRate => 60.0,
+            Metric::SnapshotSize => 1000.0,
+            Metric::SnapshotAge => 100.0,
+            Metric::InputLead => 4.0,
+            Metric::ClockDrift => 5.0,
+            Metric::DecodeFailures => 1.0,
+        }
+    }
+}
+
+/// Whether the overlay is shown, kept across games.
+#[derive(Debug, Default, Resource)]
+pub struct NetStatsVisible(bool);
+
+/// Rolling history of every [`Metric`], indexed by it.
+#[derive(Debug, Resource)]
+pub struct NetGraphs {
+    samples: [VecDeque<f32>; Metric::ALL.len()],
+    previous: NetStats,
+    sampled_at: f64,
+}
+
+impl Default for NetGraphs {
+    fn default() -> Self {
+        Self {
+            samples: std::array::from_fn(|_| VecDeque::with_capacity(GRAPH_SAMPLES)),
+            previous: NetStats::default(),
+            sampled_at: 0.0,
+        }
+    }
+}
+
+impl NetGraphs {
+    fn push(&mut self, metric: Metric, value: f32) {
+        let samples = &mut self.samples[metric as usize];
+
+        if samples.len() == GRAPH_SAMPLES {
+            samples.pop_front();
+        }
+
+        samples.push_back(value);
+    }
+}
+
+#[derive(Debug, Component)]
+pub struct NetStatsOverlay;
+
+#[derive(Debug, Component)]
+pub struct NetGraphLabel(Metric);
+
+#[derive(Debug, Component)]
+pub struct NetGraphBar {
+    metric: Metric,
+    index: usize,
+}
+
+pub fn spawn_net_stats_overlay(mut commands: Commands, visible: Res<NetStatsVisible>) {
+    let visibility = if visible.0 {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+
+    commands
+        .spawn((
+            DespawnOnExit(ClientState::InGame),
+            NetStatsOverlay,
+            Node {
+                position_type: PositionType::Absolute,
+                left: Val::Px(12.0),
+                top: Val::Px(12.0),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.0),
+                padding: UiRect::all(Val::Px(8.0)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+            GlobalZIndex(200),
+            visibility,
+        ))
+        .with_children(|overlay| {
+            for metric in Metric::ALL {
+                overlay.spawn((
+                    NetGraphLabel(metric),
+                    Text::new(metric.label(0.0)),
+                    TextFont::from_font_size(14.0),
+                    TextColor(Color::WHITE),
+                ));
+
+                overlay
+                    .spawn(Node {
+                        width: Val::Px(BAR_WIDTH * GRAPH_SAMPLES as f32),
+                        height: Val::Px(GRAPH_HEIGHT),
+                        align_items: AlignItems::FlexEnd,
+                        ..default()
+                    })
+                    .with_children(|graph| {
+                        for index in 0..GRAPH_SAMPLES {
+                            graph.spawn((
+                                NetGraphBar { metric, index },
+                                Node {
+                                    width: Val::Px(BAR_WIDTH),
+                                    height: Val::Px(0.0),
+                                    ..default()
+                                },
+                                BackgroundColor(Color::from(tailwind::GREEN_400)),
+                            ));
+                        }
+                    });
+            }
+        });
+}
+
+pub fn toggle_net_stats_overlay(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut visible: ResMut<NetStatsVisible>,
+    mut overlay: Query<&mut Visibility, With<NetStatsOverlay>>,
+) {
+    if !keys.just_pressed(KeyCode::F3) {
+        return;
+    }
+
+    visible.0 = !visible.0;
+
+    for mut visibility in overlay.iter_mut() {
+        *visibility = if visible.0 {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+/// Turns the running totals into per-second rates, one point per [`SAMPLE_INTERVAL`].
+pub fn sample_net_stats(
+    time: Res<Time>,
+    stats: Res<NetStats>,
+    input_clock: Res<InputClock>,
+    client: Option<Res<RenetClient>>,
+    mut graphs: ResMut<NetGraphs>,
+) {
+    let now = time.elapsed_secs_f64();
+    let elapsed = now - graphs.sampled_at;
+
+    if elapsed < SAMPLE_INTERVAL {
+        return;
+    }
+
+    let previous = graphs.previous;
+    let per_second = |current: u64, previous: u64| (current - previous) as f64 / elapsed;
+    let snapshots = stats.snapshots - previous.snapshots;
+    let snapshot_size = if snapshots > 0 {
+        (stats.snapshot_bytes - previous.snapshot_bytes) as f32 / snapshots as f32
+    } else {
+        0.0
+    };
+    let snapshot_age = stats
+        .last_snapshot_at
+        .map_or(0.0, |received_at| (now - received_at) * 1000.0);
+
+    // Reconnecting leaves the transport graphs flat instead of out of step with the others.
+    let (rtt, packet_loss, received, sent) = client.map_or((0.0, 0.0, 0.0, 0.0), |client| {
+        let info = client.network_info();
+        (
+            info.rtt,
+            info.packet_loss,
+            info.bytes_received_per_second,
+            info.bytes_sent_per_second,
+        )
+    });
+
+    graphs.push(Metric::Rtt, (rtt * 1000.0) as f32);
+    graphs.push(Metric::PacketLoss, (packet_loss * 100.0) as f32);
+    graphs.push(Metric::BytesReceived, (received / 1000.0) as f32);
+    graphs.push(Metric::BytesSent, (sent / 1000.0) as f32);
+
+    graphs.push(
+        Metric::SnapshotRate,
+        per_second(stats.snapshots, previous.snapshots) as f32,
+    );
+    graphs.push(Metric::SnapshotSize, snapshot_size);
+    graphs.push(Metric::SnapshotAge, snapshot_age as f32);
+    graphs.push(
+        Metric::InputRate,
+        per_second(stats.inputs_sent, previous.inputs_sent) as f32,
+    );
+    graphs.push(Metric::InputLead, input_clock.lead().unwrap_or(0.0) as f32);
+    graphs.push(
+        Metric::ClockDrift,
+        ((input_clock.drift() - 1.0) * 100.0) as f32,
+    );
+    graphs.push(
+        Metric::DecodeFailures,
+        per_second(stats.decode_failures, previous.decode_failures) as f32,
+    );
+
+    graphs.previous = *stats;
+    graphs.sampled_at = now;
+}
+
+pub fn sync_net_graphs(
+    graphs: Res<NetGraphs>,
+    mut labels: Query<(&NetGraphLabel, &mut Text)>,
+    mut bars: Query<(&NetGraphBar, &mut Node)>,
+) {
+    if !graphs.is_changed() {
+        return;
+    }
+
+    for (NetGraphLabel(metric), mut text) in labels.iter_mut() {
+        let latest = graphs.samples[*metric as usize]
+            .back()
+            .copied()
+            .unwrap_or(0.0);
+
+        **text = metric.label(latest);
+    }
+
+    for (bar, mut node) in bars.iter_mut() {
+        let samples = &graphs.samples[bar.metric as usize];
+        // Bars only grow upwards, negative values show by their magnitude.
+        let scale = samples
+            .iter()
+            .copied()
+            .map(f32::abs)
+            .fold(bar.metric.min_scale(), f32::max);
+        // Newest point on the right, empty space on the left until the graph fills up.
+        let offset = GRAPH_SAMPLES - samples.len();
+        let value = bar
+            .index
+            .checked_sub(offset)
+            .and_then(|index| samples.get(index))
+            .copied()
+            .unwrap_or(0.0);
+
+        node.height = Val::Px(GRAPH_HEIGHT * value.abs() / scale);
+    }
+}
@@ -1,21 +1,91 @@
 use bevy::prelude::*;
-use bevy_renet2::prelude::{DefaultChannel, RenetClient, client_connected};
+use bevy_renet2::prelude::{RenetClient, client_connected};
 use common::{
-    ImpactMarkData, Lobby, PlayerId, PlayerVisualState, ProjectileData, ServerMessage,
-    WeaponKind, WorldSnapshot, data,
+    BUILD_HASH, Client, ClientInput, Envelope, GameEvent, Hello, INPUT_REDUNDANCY, ImpactMark,
+    Lobby, PROTOCOL_VERSION, PlayerId, PrivatePlayerData, ProjectileData, ServerMessage, TickRate,
+    WeaponKind,
+    data::{self, Channel, DecodeError},
+    delta::SnapshotHistory,
+    map,
+    replication::{NetworkEntities, NetworkId, receive_replication},
 };
 
-use crate::render::{ImpactMarkVisual, ProjectileVisual, player_body_mesh};
+use crate::{
+    clock::InputClock,
+    connection::ConnectionSettings,
+    interpolation::{InterpolationBuffer, Sample, SnapshotClock},
+    menu::ClientState,
+    prediction::{self, AuthoritativeMovement, InputHistory},
+    render::{ProjectileVisual, player_body_mesh},
+};
 
 pub struct Plugin;
 
 impl bevy::prelude::Plugin for Plugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<WeaponAudio>()
-            .add_systems(Update, (send_input, recv_players_pos, recv_connectivity).run_if(client_connected));
+            .init_resource::<SnapshotHistory>()
+            .init_resource::<NetStats>()
+            .add_message::<SnapshotReceived>()
+            .add_message::<GameEventReceived>()
+            .add_systems(OnEnter(ClientState::InGame), send_hello)
+            .add_systems(OnExit(ClientState::InGame), clear_server_state)
+            .add_systems(
+                Update,
+                (
+                    (recv_players_pos, recv_connectivity).run_if(client_connected),
+                    apply_world_snapshot.after(recv_players_pos),
+                    apply_game_events.after(recv_connectivity),
+                    receive_replication.run_if(client_connected),
+                    (
+                        despawn_replicated_on_exit,
+                        spawn_remote_players,
+                        sync_lobby,
+                        spawn_impact_visuals,
+                    )
+                        .after(receive_replication),
+                )
+                    .run_if(in_state(ClientState::InGame)),
+            )
+            .add_systems(
+                FixedUpdate,
+                send_input
+                    .after(prediction::predict_local_player)
+                    .run_if(in_state(ClientState::InGame).and(client_connected)),
+            );
     }
 }
 
+/// A snapshot for `tick` was rebuilt and stored in the [`SnapshotHistory`].
+#[derive(Debug, Message)]
+struct SnapshotReceived {
+    tick: u32,
+}
+
+/// Something the server told us happened, in the order it was sent.
+#[derive(Debug, Message)]
+struct GameEventReceived(GameEvent);
+
+/// What the server tells only us about our own player.
+#[derive(Debug, Resource, Deref)]
+pub struct PrivateState(pub PrivatePlayerData);
+
+/// Running totals of what we exchanged with the server, sampled by the network stats overlay.
+#[derive(Debug, Default, Clone, Copy, Resource)]
+pub struct NetStats {
+    pub snapshots: u64,
+    pub snapshot_bytes: u64,
+    pub inputs_sent: u64,
+    /// Messages from the server we could not decode, on any channel.
+    pub decode_failures: u64,
+    /// When the newest snapshot arrived, in seconds since startup.
+    pub last_snapshot_at: Option<f64>,
+}
+
+/// Why we are not connected, shown to the player.
+#[derive(Debug, Resource)]
+pub struct DisconnectReason(pub String);
+
 #[derive(Debug, Resource)]
 struct WeaponAudio {
     rifle: Handle<AudioSource>,
@@ -33,113 +103,247 @@ impl FromWorld for WeaponAudio {
     }
 }
 
-fn send_input(player_input: Res<common::ClientInput>, mut client: ResMut<RenetClient>) {
-    let input_message = data::encode(&*player_input);
+fn send_hello(mut client: ResMut<RenetClient>, settings: Res<ConnectionSettings>) {
+    let hello = Hello {
+        build_hash: BUILD_HASH.to_owned(),
+        compression: settings.compression(),
+    };
+
+    client.send_message(Channel::GameEvent, data::encode(&Envelope::Hello(hello)));
+}
+
+/// Sends this tick's input along with the previous ones, so a lost packet is covered by the next.
+fn send_input(
+    history: Res<InputHistory>,
+    mut client: ResMut<RenetClient>,
+    mut stats: ResMut<NetStats>,
+) {
+    let inputs: Vec<ClientInput> = history.recent(INPUT_REDUNDANCY).cloned().collect();
+
+    if inputs.is_empty() {
+        return;
+    }
 
-    client.send_message(DefaultChannel::ReliableOrdered, input_message);
+    client.send_message(Channel::Input, data::encode(&Envelope::Input(inputs)));
+    stats.inputs_sent += 1;
 }
 
 fn recv_connectivity(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     mut client: ResMut<RenetClient>,
-    mut lobby: ResMut<Lobby>,
-    player_id: Res<PlayerId>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut game_events: MessageWriter<GameEventReceived>,
+    mut stats: ResMut<NetStats>,
 ) {
-    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
-        let event: ServerMessage = data::decode(&message);
+    while let Some(message) = client.receive_message(Channel::GameEvent) {
+        let event = match data::decode(&message) {
+            Ok(Envelope::Server(event)) => event,
+            Ok(Envelope::Events { events, .. }) => {
+                game_events.write_batch(events.into_iter().map(GameEventReceived));
+                continue;
+            }
+            Ok(_) => {
+                warn!("Dropping unexpected message on the server message channel");
+                continue;
+            }
+            Err(DecodeError::VersionMismatch { found }) => {
+                let reason = format!(
+                    "Incompatible server: it speaks protocol version {}, this client speaks {}",
+                    found, PROTOCOL_VERSION
+                );
+
+                error!("{}", reason);
+                commands.insert_resource(DisconnectReason(reason));
+                stats.decode_failures += 1;
+                continue;
+            }
+            Err(e) => {
+                warn!("Dropping server message: {}", e);
+                stats.decode_failures += 1;
+                continue;
+            }
+        };
 
         match event {
-            ServerMessage::ClientConnected { id } => {
-                info!("Player {} connected.", id);
-
-                if id == player_id.0 {
-                    commands
-                        .get_entity(lobby.players[&id])
-                        .expect("Player to be in lobby")
-                        .insert(PlayerVisualState::default());
-                } else {
-                    let client = commands.spawn((
-                        PlayerVisualState::default(),
-                        children![player_body_mesh(
-                            meshes.add(Cuboid::from_size(Vec3::splat(1.0))),
-                            materials.add(Color::srgb(0.8, 0.7, 0.6)),
-                        )],
-                    ));
-
-                    lobby.players.insert(id, client.id());
+            ServerMessage::Welcome {
+                tick_rate,
+                map: map_name,
+            } => {
+                if !map::MAPS.contains(&map_name.as_str()) {
+                    let reason =
+                        format!("The server runs map {}, which this client lacks", map_name);
+
+                    error!("{}", reason);
+                    commands.insert_resource(DisconnectReason(reason));
+                    client.disconnect();
+                    continue;
                 }
+
+                info!("Joined server running {} at {} Hz.", map_name, tick_rate);
+
+                // Prediction has to step at the same rate the server simulates.
+                fixed_time.set_timestep_hz(tick_rate);
+                commands.insert_resource(TickRate(tick_rate));
             }
-            ServerMessage::ClientDisconnected { id } => {
-                info!("Player {} disconnected.", id);
-                if let Some(player_entity) = lobby.players.remove(&id) {
-                    commands.entity(player_entity).despawn();
-                }
+            ServerMessage::Kicked { reason } => {
+                error!("Kicked by the server: {}", reason);
+                commands.insert_resource(DisconnectReason(reason));
             }
         }
     }
 }
 
-fn recv_players_pos(
+/// Forgets everything the server told us, the next connection starts from scratch.
+///
+/// The entities themselves are despawned on leaving [`ClientState::InGame`].
+fn clear_server_state(
     mut commands: Commands,
+    mut lobby: ResMut<Lobby>,
+    mut history: ResMut<SnapshotHistory>,
+    mut clock: ResMut<SnapshotClock>,
+    mut input: ResMut<ClientInput>,
+    mut stats: ResMut<NetStats>,
+    mut network_entities: ResMut<NetworkEntities>,
+) {
+    commands.remove_resource::<PrivateState>();
+    lobby.players.clear();
+    *network_entities = NetworkEntities::default();
+    *history = SnapshotHistory::default();
+    *clock = SnapshotClock::default();
+    input.snapshot_ack = 0;
+    stats.last_snapshot_at = None;
+}
+
+fn recv_players_pos(
     mut client: ResMut<RenetClient>,
+    mut history: ResMut<SnapshotHistory>,
+    mut input: ResMut<ClientInput>,
+    mut received: MessageWriter<SnapshotReceived>,
+    mut stats: ResMut<NetStats>,
+    time: Res<Time>,
+) {
+    while let Some(message) = client.receive_message(Channel::Snapshot) {
+        let delta = match data::decode(&message) {
+            Ok(Envelope::Snapshot(delta)) => delta,
+            Ok(_) => {
+                warn!("Dropping unexpected message on the snapshot channel");
+                continue;
+            }
+            Err(e) => {
+                warn!("Dropping world snapshot: {}", e);
+                stats.decode_failures += 1;
+                continue;
+            }
+        };
+
+        stats.snapshots += 1;
+        stats.snapshot_bytes += message.len() as u64;
+        stats.last_snapshot_at = Some(time.elapsed_secs_f64());
+
+        let tick = delta.tick;
+        let baseline = delta.baseline_tick.and_then(|tick| history.get(tick));
+
+        let Some(snapshot) = delta.apply(baseline) else {
+            // The baseline already left our history or the delta is malformed, the server will fall
+            // back to a full snapshot.
+            continue;
+        };
+
+        history.push(snapshot);
+        input.snapshot_ack = input.snapshot_ack.max(tick);
+        received.write(SnapshotReceived { tick });
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn apply_world_snapshot(
+    mut commands: Commands,
+    mut received: MessageReader<SnapshotReceived>,
+    history: Res<SnapshotHistory>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     lobby: Res<Lobby>,
-    projectile_visuals: Query<(Entity, &ProjectileVisual)>,
-    impact_visuals: Query<(Entity, &ImpactMarkVisual)>,
+    time: Res<Time>,
+    mut clock: ResMut<SnapshotClock>,
+    mut input_clock: ResMut<InputClock>,
+    tick_rate: Res<TickRate>,
+    mut remote_players: Query<
+        (&mut InterpolationBuffer, &mut Visibility),
+        (Without<ProjectileVisual>, Without<PlayerId>),
+    >,
+    mut projectile_visuals: Query<(Entity, &ProjectileVisual, &mut InterpolationBuffer)>,
     player_id: Res<PlayerId>,
-    weapon_audio: Res<WeaponAudio>,
+    mut authoritative_movement: MessageWriter<AuthoritativeMovement>,
 ) {
-    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-        let snapshot: WorldSnapshot = data::decode(&message);
+    for SnapshotReceived { tick } in received.read() {
+        let Some(snapshot) = history.get(*tick) else {
+            continue;
+        };
+        let now = time.elapsed_secs_f64();
+
+        clock.observe(snapshot.tick, now, &tick_rate);
 
         for player in snapshot.players.iter() {
-            if let Some(player_entity) = lobby.players.get(&player.id) {
-                commands.entity(*player_entity).insert((
-                    Transform {
+            let Some(&player_entity) = lobby.players.get(&player.id) else {
+                continue;
+            };
+
+            if player.id == player_id.0 {
+                // Nothing to reconcile without knowing which inputs the server simulated.
+                if let Some(private) = &snapshot.private {
+                    authoritative_movement.write(AuthoritativeMovement {
+                        last_input_sequence: private.last_input_sequence,
                         translation: player.pos.into(),
-                        rotation: (&player.rot).into(),
-                        ..Default::default()
-                    },
-                    PlayerVisualState {
-                        alive: player.alive,
+                        velocity: player.vel.into(),
+                        grounded: player.grounded,
+                        jump_queued: player.jump_queued,
                         crouched: player.crouched,
-                        health: player.health,
-                        weapon: player.weapon,
-                        ammo_in_mag: player.ammo_in_mag,
-                    },
-                ));
+                    });
+                }
+            } else if let Ok((mut buffer, mut visibility)) = remote_players.get_mut(player_entity) {
+                // Back in range, whatever it did while out of sight is not worth interpolating.
+                if *visibility == Visibility::Hidden {
+                    buffer.clear();
+                    *visibility = Visibility::Inherited;
+                }
+
+                buffer.push(Sample {
+                    tick: snapshot.tick,
+                    translation: player.pos.into(),
+                    rotation: (&player.rot).into(),
+                    velocity: player.vel.into(),
+                });
+            }
+        }
+
+        match &snapshot.private {
+            Some(private) => {
+                input_clock.observe(snapshot.tick, private.buffered_inputs);
+                commands.insert_resource(PrivateState(private.clone()));
             }
+            None => commands.remove_resource::<PrivateState>(),
         }
 
-        let _ = player_id;
+        // Players out of our area of interest are left out of the snapshot.
+        for (id, &player_entity) in lobby.players.iter() {
+            if snapshot.players.iter().any(|player| player.id == *id) {
+                continue;
+            }
 
-        for fired_projectile in snapshot.fired_projectiles.iter() {
-            commands.spawn((
-                AudioPlayer::new(match fired_projectile.weapon {
-                    WeaponKind::Rifle => weapon_audio.rifle.clone(),
-                    WeaponKind::Pistol => weapon_audio.pistol.clone(),
-                }),
-                PlaybackSettings::DESPAWN,
-            ));
+            if let Ok((_, mut visibility)) = remote_players.get_mut(player_entity) {
+                *visibility = Visibility::Hidden;
+            }
         }
 
         sync_projectile_visuals(
             &mut commands,
             &mut meshes,
             &mut materials,
-            &projectile_visuals,
+            &mut projectile_visuals,
+            snapshot.tick,
+            clock.render_tick(now, &tick_rate),
             &snapshot.projectiles,
         );
-        sync_impact_visuals(
-            &mut commands,
-            &mut meshes,
-            &mut materials,
-            &impact_visuals,
-            &snapshot.impact_marks,
-        );
     }
 }
 
@@ -147,7 +351,9 @@ fn sync_projectile_visuals(
     commands: &mut Commands,
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<StandardMaterial>,
-    projectile_visuals: &Query<(Entity, &ProjectileVisual)>,
+    projectile_visuals: &mut Query<(Entity, &ProjectileVisual, &mut InterpolationBuffer)>,
+    tick: u32,
+    render_tick: Option<f64>,
     projectiles: &[ProjectileData],
 ) {
     let mut seen_ids = Vec::with_capacity(projectiles.len());
@@ -155,14 +361,6 @@ fn sync_projectile_visuals(
     for projectile in projectiles {
         seen_ids.push(projectile.id);
 
-        let mut existing = None;
-        for (entity, visual) in projectile_visuals.iter() {
-            if visual.id == projectile.id {
-                existing = Some(entity);
-                break;
-            }
-        }
-
         let velocity: Vec3 = projectile.vel.into();
         let mut transform = Transform::from_translation(projectile.pos.into());
         if velocity.length_squared() > 0.0 {
@@ -170,11 +368,27 @@ fn sync_projectile_visuals(
         }
         transform.scale = Vec3::new(0.03, 0.03, 0.45);
 
-        if let Some(entity) = existing {
-            commands.entity(entity).insert(transform);
+        let sample = Sample {
+            tick,
+            translation: transform.translation,
+            rotation: transform.rotation,
+            velocity,
+        };
+
+        let existing = projectile_visuals
+            .iter_mut()
+            .find(|(_, visual, _)| visual.id == projectile.id);
+
+        if let Some((_, _, mut buffer)) = existing {
+            buffer.push(sample);
         } else {
+            let mut buffer = InterpolationBuffer::default();
+            buffer.push(sample);
+
             commands.spawn((
+                DespawnOnExit(ClientState::InGame),
                 ProjectileVisual { id: projectile.id },
+                buffer,
                 Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
                 MeshMaterial3d(materials.add(StandardMaterial {
                     base_color: Color::srgb(1.0, 0.7, 0.2),
@@ -186,41 +400,128 @@ fn sync_projectile_visuals(
         }
     }
 
-    for (entity, visual) in projectile_visuals.iter() {
-        if !seen_ids.contains(&visual.id) {
+    for (entity, visual, buffer) in projectile_visuals.iter() {
+        if seen_ids.contains(&visual.id) {
+            continue;
+        }
+
+        // Keep the visual until interpolation has caught up with its last known state.
+        let caught_up = render_tick
+            .zip(buffer.latest_tick())
+            .is_none_or(|(render_tick, latest)| render_tick >= latest as f64);
+
+        if caught_up {
             commands.entity(entity).despawn();
         }
     }
 }
 
-fn sync_impact_visuals(
-    commands: &mut Commands,
-    meshes: &mut Assets<Mesh>,
-    materials: &mut Assets<StandardMaterial>,
-    impact_visuals: &Query<(Entity, &ImpactMarkVisual)>,
-    impacts: &[ImpactMarkData],
+fn apply_game_events(
+    mut commands: Commands,
+    mut received: MessageReader<GameEventReceived>,
+    weapon_audio: Res<WeaponAudio>,
 ) {
-    for impact in impacts {
-        let mut exists = false;
-
-        for (_, visual) in impact_visuals.iter() {
-            if visual.id == impact.id {
-                exists = true;
-                break;
+    for GameEventReceived(event) in received.read() {
+        match event {
+            GameEvent::ShotFired { weapon, .. } => {
+                commands.spawn((
+                    AudioPlayer::new(match weapon {
+                        WeaponKind::Rifle => weapon_audio.rifle.clone(),
+                        WeaponKind::Pistol => weapon_audio.pistol.clone(),
+                    }),
+                    PlaybackSettings::DESPAWN,
+                ));
+            }
+            GameEvent::PlayerDamaged {
+                id,
+                attacker,
+                amount,
+            } => {
+                info!(
+                    "Player {} took {} damage from player {}.",
+                    id, amount, attacker
+                );
+            }
+            GameEvent::PlayerKilled { id, killer } => {
+                info!("Player {} was killed by player {}.", id, killer);
+            }
+            GameEvent::PlayerRespawned { id } => {
+                info!("Player {} respawned.", id);
             }
         }
+    }
+}
 
-        if exists {
+/// Gives the players the server replicates a body, our own is dressed by the render plugin.
+fn spawn_remote_players(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    player_id: Res<PlayerId>,
+    players: Query<(Entity, &Client), Added<Client>>,
+) {
+    for (entity, client) in players.iter() {
+        if client.id == player_id.0 {
             continue;
         }
 
+        commands.entity(entity).insert((
+            InterpolationBuffer::default(),
+            Transform::default(),
+            // Shown once a snapshot places it near us.
+            Visibility::Hidden,
+            children![player_body_mesh(
+                meshes.add(Cuboid::from_size(Vec3::splat(1.0))),
+                materials.add(Color::srgb(0.8, 0.7, 0.6)),
+            )],
+        ));
+    }
+}
+
+/// Keeps the [`Lobby`] in line with the players the server replicates.
+fn sync_lobby(
+    mut lobby: ResMut<Lobby>,
+    mut removed: RemovedComponents<Client>,
+    players: Query<(Entity, &Client), Added<Client>>,
+) {
+    let removed: Vec<Entity> = removed.read().collect();
+    lobby.players.retain(|id, entity| {
+        let connected = !removed.contains(entity);
+        if !connected {
+            info!("Player {} disconnected.", id);
+        }
+        connected
+    });
+
+    for (entity, client) in players.iter() {
+        info!("Player {} connected.", client.id);
+        lobby.players.insert(client.id, entity);
+    }
+}
+
+/// Replicated entities belong to the server we are connected to.
+fn despawn_replicated_on_exit(mut commands: Commands, spawned: Query<Entity, Added<NetworkId>>) {
+    for entity in spawned.iter() {
+        commands
+            .entity(entity)
+            .insert(DespawnOnExit(ClientState::InGame));
+    }
+}
+
+/// Dresses marks as they are replicated, the server despawns the oldest ones.
+fn spawn_impact_visuals(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    marks: Query<(Entity, &ImpactMark), Added<ImpactMark>>,
+) {
+    for (entity, impact) in marks.iter() {
         let normal: Vec3 = impact.normal.into();
         let mut transform = Transform::from_translation(Vec3::from(impact.pos) + normal * 0.01);
         transform.look_to(normal, Vec3::Y);
         transform.scale = Vec3::new(0.18, 0.18, 0.01);
 
-        commands.spawn((
-            ImpactMarkVisual { id: impact.id },
+        commands.entity(entity).insert((
             Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
             MeshMaterial3d(materials.add(Color::srgb(0.08, 0.08, 0.08))),
             transform,
@@ -0,0 +1,154 @@
+//! Size and cost of LZ4 on typical server messages, the basis for `COMPRESSION_THRESHOLD`.
+//!
+//! Usage: `cargo bench -p common --bench compression`, sizes are printed before each group.
+
+use std::{f32::consts::TAU, hint::black_box};
+
+use common::{
+    data::{self, COMPRESSION_THRESHOLD},
+    delta::SnapshotDelta,
+    replication::{ComponentUpdate, NetworkId, ReplicationMessage},
+    CameraInput, ClientData, Envelope, GameEvent, ImpactMark, PrivatePlayerData, ProjectileData,
+    WeaponKind, WorldSnapshot,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// A round in progress, everyone moving so that consecutive ticks differ.
+fn world(tick: u32, players: u64, projectiles: u64) -> WorldSnapshot {
+    let t = tick as f32 / 64.0;
+    let spread = |i: u64, speed: f32| {
+        let angle = i as f32 * 2.4;
+        let vel = [angle.cos() * speed, 0.0, angle.sin() * speed];
+        let pos = [
+            (angle.sin() * 24.0 + vel[0] * t).clamp(-32.0, 32.0),
+            (i % 3) as f32 * 4.0 + 1.0,
+            (angle.cos() * 24.0 + vel[2] * t).clamp(-32.0, 32.0),
+        ];
+        (pos, vel)
+    };
+
+    WorldSnapshot {
+        tick,
+        players: (0..players)
+            .map(|i| {
+                let (pos, vel) = spread(i, 6.0);
+                ClientData {
+                    id: i,
+                    pos,
+                    vel,
+                    rot: CameraInput {
+                        yaw: (i as f32 + t).rem_euclid(TAU),
+                        pitch: (i as f32 * 0.7).sin() * 0.5,
+                        ..Default::default()
+                    },
+                    grounded: i % 4 != 0,
+                    jump_queued: false,
+                    crouched: i % 5 == 0,
+                }
+            })
+            .collect(),
+        projectiles: (0..projectiles)
+            .map(|i| {
+                let (pos, vel) = spread(i + 100, 300.0);
+                ProjectileData { id: i, pos, vel }
+            })
+            .collect(),
+        private: Some(PrivatePlayerData {
+            reload: None,
+            fire_cooldown: 0.0,
+            buffered_inputs: 2,
+            last_input_sequence: tick,
+        }),
+    }
+}
+
+fn messages() -> Vec<(String, Envelope)> {
+    let full = |players, projectiles| {
+        Envelope::Snapshot(SnapshotDelta::encode(None, &world(0, players, projectiles)))
+    };
+    let delta = |players, projectiles| {
+        Envelope::Snapshot(SnapshotDelta::encode(
+            Some(&world(0, players, projectiles)),
+            &world(4, players, projectiles),
+        ))
+    };
+    let marks = Envelope::Replication(ReplicationMessage {
+        spawned: (0..256).map(NetworkId).collect(),
+        updated: (0..256)
+            .map(|i| ComponentUpdate {
+                entity: NetworkId(i),
+                kind: 0,
+                payload: data::to_payload(&ImpactMark {
+                    pos: [i as f32 * 0.25 - 32.0, 1.5, (i % 16) as f32],
+                    normal: [0.0, 0.0, 1.0],
+                }),
+            })
+            .collect(),
+        despawned: Vec::new(),
+    });
+    let events = Envelope::Events {
+        tick: 1,
+        events: vec![
+            GameEvent::ShotFired {
+                projectile_id: 7,
+                shooter: 1,
+                weapon: WeaponKind::Rifle,
+                pos: [3.0, 1.6, -12.0],
+            },
+            GameEvent::PlayerDamaged {
+                id: 2,
+                attacker: 1,
+                amount: 25.0,
+            },
+        ],
+    };
+
+    let mut messages = Vec::new();
+    for players in [2, 8, 32] {
+        messages.push((
+            format!("full snapshot, {players} players"),
+            full(players, players / 2),
+        ));
+        messages.push((
+            format!("delta snapshot, {players} players"),
+            delta(players, players / 2),
+        ));
+    }
+    messages.push(("256 impact marks".to_owned(), marks));
+    messages.push(("events".to_owned(), events));
+    messages
+}
+
+fn compression(c: &mut Criterion) {
+    for (name, message) in messages() {
+        let payload = data::to_payload(&message);
+        let compressed = lz4_flex::compress_prepend_size(&payload);
+
+        println!(
+            "{name}: {} bytes, {} with LZ4{}",
+            payload.len(),
+            compressed.len(),
+            if payload.len() < COMPRESSION_THRESHOLD {
+                ", sent as is"
+            } else {
+                ""
+            }
+        );
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_function("serialize", |b| {
+            b.iter(|| data::to_payload(black_box(&message)))
+        });
+        group.bench_function("compress", |b| {
+            b.iter(|| lz4_flex::compress_prepend_size(black_box(&payload)))
+        });
+        group.bench_function("decompress", |b| {
+            b.iter(|| lz4_flex::decompress_size_prepended(black_box(&compressed)).unwrap())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -0,0 +1,163 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{SystemTime, SystemTimeError},
+};
+
+use bevy_renet2::netcode::{
+    generate_random_bytes, ConnectToken, TokenGenerationError, NETCODE_KEY_BYTES,
+    NETCODE_USER_DATA_BYTES,
+};
+
+use crate::PROTOCOL_ID;
+
+/// Seconds a freshly issued token can be used to start a connection.
+pub const TOKEN_EXPIRE_SECONDS: u64 = 300;
+/// Seconds without packets before either side drops the connection.
+pub const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+/// Longest player name that fits the token user data, in bytes.
+pub const PLAYER_NAME_MAX_LEN: usize = NETCODE_USER_DATA_BYTES - 1;
+
+/// Mints a connect token for `player_name`, valid on any of `server_addresses`.
+///
+/// The token is signed with the server's private key, so its client id and name can't be forged.
+pub fn issue_connect_token(
+    private_key: &[u8; NETCODE_KEY_BYTES],
+    server_addresses: Vec<SocketAddr>,
+    player_name: &str,
+) -> Result<ConnectToken, AuthError> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(AuthError::Clock)?;
+
+    let user_data = encode_player_name(player_name)?;
+    let client_id = u64::from_le_bytes(generate_random_bytes());
+
+    ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        TOKEN_TIMEOUT_SECONDS,
+        0,
+        server_addresses,
+        Some(&user_data),
+        private_key,
+    )
+    .map_err(AuthError::Token)
+}
+
+/// Parses a private key written as 64 hex digits.
+pub fn parse_private_key(hex: &str) -> Result<[u8; NETCODE_KEY_BYTES], AuthError> {
+    let hex = hex.trim();
+
+    // Checked digit by digit, `from_str_radix` would also take a leading sign.
+    if hex.len() != NETCODE_KEY_BYTES * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AuthError::InvalidKey);
+    }
+
+    let mut key = [0; NETCODE_KEY_BYTES];
+
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| AuthError::InvalidKey)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| AuthError::InvalidKey)?;
+    }
+
+    Ok(key)
+}
+
+/// Length-prefixed UTF-8, the rest of the user data is left zeroed.
+fn encode_player_name(name: &str) -> Result<[u8; NETCODE_USER_DATA_BYTES], AuthError> {
+    if name.is_empty() || name.len() > PLAYER_NAME_MAX_LEN {
+        return Err(AuthError::InvalidName);
+    }
+
+    let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+    user_data[0] = name.len() as u8;
+    user_data[1..=name.len()].copy_from_slice(name.as_bytes());
+
+    Ok(user_data)
+}
+
+/// Reads back the player name a token was issued for.
+pub fn decode_player_name(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<String> {
+    let (len, name) = user_data.split_first()?;
+    let name = name.get(..*len as usize)?;
+
+    String::from_utf8(name.to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// Not 64 hex digits.
+    InvalidKey,
+    /// Empty or longer than [`PLAYER_NAME_MAX_LEN`].
+    InvalidName,
+    /// The system clock is set before the Unix epoch.
+    Clock(SystemTimeError),
+    Token(TokenGenerationError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKey => write!(
+                f,
+                "private key must be {} hex digits",
+                NETCODE_KEY_BYTES * 2
+            ),
+            Self::InvalidName => write!(
+                f,
+                "player name must be between 1 and {} bytes",
+                PLAYER_NAME_MAX_LEN
+            ),
+            Self::Clock(e) => write!(f, "system clock is invalid: {}", e),
+            Self::Token(e) => write!(f, "could not generate connect token: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_keys() {
+        let hex = "00ff7A".repeat(11)[..NETCODE_KEY_BYTES * 2].to_owned();
+        let key = parse_private_key(&format!(" {hex}\n")).unwrap();
+
+        assert_eq!(key[..3], [0x00, 0xff, 0x7a]);
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let valid = "ab".repeat(NETCODE_KEY_BYTES);
+
+        for key in [
+            &valid[2..],
+            &format!("{valid}ab"),
+            &format!("+b{}", &valid[2..]),
+            &format!("-b{}", &valid[2..]),
+            &format!("ag{}", &valid[2..]),
+            &format!("é{}", &valid[2..]),
+        ] {
+            assert!(
+                matches!(parse_private_key(key), Err(AuthError::InvalidKey)),
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn player_name_round_trip() {
+        let user_data = encode_player_name("player").unwrap();
+
+        assert_eq!(decode_player_name(&user_data).as_deref(), Some("player"));
+        assert!(encode_player_name("").is_err());
+        assert!(encode_player_name(&"a".repeat(PLAYER_NAME_MAX_LEN + 1)).is_err());
+    }
+}
@@ -1,29 +1,767 @@
-use bevy_renet2::prelude::ConnectionConfig;
+use std::{f32::consts::TAU, fmt, time::Duration};
+
+use bevy::prelude::Component;
+use bevy_renet2::prelude::{ChannelConfig, ConnectionConfig, SendType};
 use bytes::Bytes;
+use lz4_flex::block::DecompressError;
 use rkyv::{
     api::high::{to_bytes_in, HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    de::Pool,
     from_bytes,
     rancor::{Error, Strategy},
     ser::allocator::ArenaHandle,
+    util::AlignedVec,
     Archive, Deserialize, Serialize,
 };
 
+use crate::{map, CameraInput, ClientData, MAX_CAMERA_PITCH, MAX_CAMERA_ROLL, PROTOCOL_VERSION};
+
+/// Protocol version, then the [`Compression`] of the payload.
+const HEADER_LEN: usize = size_of::<u16>() + 1;
+/// Payloads smaller than this are sent as is: LZ4 saves them only tens of bytes, for a couple of
+/// microseconds per recipient. Larger snapshots shrink by a fifth to a third, see the
+/// `compression` bench.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+/// Largest payload a compressed message may claim to expand to, the size of the biggest channel.
+const MAX_DECOMPRESSED_LEN: usize = 1024 * 1024;
+/// Fastest a player's velocity is encoded up to per axis, faster ones saturate.
+pub const PLAYER_VELOCITY_RANGE: f32 = 64.0;
+/// Fastest a projectile's velocity is encoded up to per axis, above any muzzle speed plus a
+/// lifetime of gravity.
+pub const PROJECTILE_VELOCITY_RANGE: f32 = 1024.0;
+
+/// Named renet channels, both directions share the same layout.
+///
+/// Channels are listed by priority: earlier ones get first pick of each tick's byte budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Handshake, connectivity and gameplay events, must arrive and in order.
+    GameEvent,
+    /// Replicated entities and components, see [`crate::replication`], must arrive and in order.
+    Replication,
+    /// Player input, each packet carries recent inputs so a lost one is covered by the next.
+    Input,
+    /// World snapshots, superseded by the next one so they are never resent.
+    Snapshot,
+    /// Chat, kept apart so a burst of text never delays game events.
+    Chat,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::GameEvent,
+        Channel::Replication,
+        Channel::Input,
+        Channel::Snapshot,
+        Channel::Chat,
+    ];
+
+    fn config(self) -> ChannelConfig {
+        let (max_memory_usage_bytes, send_type) = match self {
+            Channel::GameEvent => (
+                1024 * 1024,
+                SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            ),
+            Channel::Replication => (
+                1024 * 1024,
+                SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            ),
+            Channel::Input => (
+                16 * 1024,
+                SendType::Unreliable {
+                    ordered_reliable_substrate: false,
+                },
+            ),
+            Channel::Snapshot => (
+                512 * 1024,
+                SendType::Unreliable {
+                    ordered_reliable_substrate: false,
+                },
+            ),
+            Channel::Chat => (
+                64 * 1024,
+                SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(500),
+                },
+            ),
+        };
+
+        ChannelConfig {
+            channel_id: self.into(),
+            max_memory_usage_bytes,
+            send_type,
+        }
+    }
+}
+
+impl From<Channel> for u8 {
+    fn from(channel: Channel) -> Self {
+        channel as u8
+    }
+}
+
 pub fn renet_config() -> ConnectionConfig {
-    // change here if we need more reliable/unrealiable channels
-    ConnectionConfig::test()
+    ConnectionConfig::from_shared_channels(Channel::ALL.map(Channel::config).to_vec())
+}
+
+/// How a payload is compressed, advertised by the client in its `Hello`.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize, Component,
+)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format, the uncompressed size prepended.
+    Lz4,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Self::None),
+            1 => Some(Self::Lz4),
+            _ => None,
+        }
+    }
 }
 
-pub fn encode(
-    input: &impl for<'a> Serialize<HighSerializer<Vec<u8>, ArenaHandle<'a>, Error>>,
-) -> Bytes {
-    Bytes::from(to_bytes_in::<_, Error>(input, Vec::new()).unwrap())
+/// Anything that can be sent, see [`encode`].
+pub trait Encode: for<'a> Serialize<HighSerializer<Vec<u8>, ArenaHandle<'a>, Error>> {}
+
+impl<T> Encode for T where T: for<'a> Serialize<HighSerializer<Vec<u8>, ArenaHandle<'a>, Error>> {}
+
+/// Anything that can be received, see [`decode`].
+pub trait Decode:
+    Archive<
+        Archived: for<'a> CheckBytes<HighValidator<'a, Error>>
+                      + Deserialize<Self, Strategy<Pool, Error>>,
+    > + Sized
+{
 }
 
-pub fn decode<D>(input: &[u8]) -> D
+impl<T> Decode for T
 where
-    D: Archive,
-    D::Archived: for<'a> rkyv::bytecheck::CheckBytes<HighValidator<'a, Error>>
-        + Deserialize<D, Strategy<rkyv::de::Pool, Error>>,
+    T: Archive,
+    T::Archived:
+        for<'a> CheckBytes<HighValidator<'a, Error>> + Deserialize<T, Strategy<Pool, Error>>,
 {
-    from_bytes::<D, Error>(input).unwrap()
+}
+
+/// Serializes a payload behind a [`PROTOCOL_VERSION`] header, uncompressed.
+pub fn encode(input: &impl Encode) -> Bytes {
+    encode_with(input, Compression::None)
+}
+
+/// Like [`encode`], compressing payloads of at least [`COMPRESSION_THRESHOLD`] bytes when that
+/// makes them smaller.
+pub fn encode_with(input: &impl Encode, compression: Compression) -> Bytes {
+    let payload = to_payload(input);
+
+    let (compression, payload) = match compression {
+        Compression::Lz4 if payload.len() >= COMPRESSION_THRESHOLD => {
+            let compressed = lz4_flex::compress_prepend_size(&payload);
+
+            if compressed.len() < payload.len() {
+                (Compression::Lz4, compressed)
+            } else {
+                (Compression::None, payload)
+            }
+        }
+        _ => (Compression::None, payload),
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    frame.push(compression.tag());
+    frame.extend_from_slice(&payload);
+
+    Bytes::from(frame)
+}
+
+/// Decodes anything [`encode_with`] produced, whatever its compression.
+pub fn decode<D: Decode>(input: &[u8]) -> Result<D, DecodeError> {
+    let (version, rest) = input
+        .split_first_chunk::<{ size_of::<u16>() }>()
+        .ok_or(DecodeError::Truncated)?;
+
+    // Checked before anything else, another version may lay out the rest of the header differently.
+    let version = u16::from_le_bytes(*version);
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::VersionMismatch { found: version });
+    }
+
+    let (&tag, payload) = rest.split_first().ok_or(DecodeError::Truncated)?;
+
+    match Compression::from_tag(tag).ok_or(DecodeError::UnknownCompression(tag))? {
+        Compression::None => from_payload(payload),
+        Compression::Lz4 => {
+            let (len, _) = payload
+                .split_first_chunk::<{ size_of::<u32>() }>()
+                .ok_or(DecodeError::Truncated)?;
+            let len = u32::from_le_bytes(*len) as usize;
+
+            // Checked up front, the claimed size is allocated before decompressing.
+            if len > MAX_DECOMPRESSED_LEN {
+                return Err(DecodeError::TooLarge(len));
+            }
+
+            let decompressed =
+                lz4_flex::decompress_size_prepended(payload).map_err(DecodeError::Decompress)?;
+
+            from_payload(&decompressed)
+        }
+    }
+}
+
+/// Serializes a value without any header, to be nested in a message that has one.
+pub fn to_payload(input: &impl Encode) -> Vec<u8> {
+    to_bytes_in::<_, Error>(input, Vec::new()).unwrap()
+}
+
+/// Reads back what [`to_payload`] wrote.
+pub fn from_payload<D: Decode>(payload: &[u8]) -> Result<D, DecodeError> {
+    // Headers and nesting shift the archive off its alignment, which rkyv validates.
+    let mut aligned = AlignedVec::<16>::with_capacity(payload.len());
+    aligned.extend_from_slice(payload);
+
+    from_bytes::<D, Error>(&aligned).map_err(DecodeError::Malformed)
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Too short to even carry the version header.
+    Truncated,
+    /// Sent by a peer speaking another protocol version, the payload can't be trusted.
+    VersionMismatch { found: u16 },
+    /// Compressed with a scheme this build does not know.
+    UnknownCompression(u8),
+    /// Claims to expand to more than any channel could hold.
+    TooLarge(usize),
+    /// Does not decompress, either corrupted or sent by a misbehaving peer.
+    Decompress(DecompressError),
+    /// Failed validation, either corrupted or sent by a misbehaving peer.
+    Malformed(Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "truncated payload"),
+            Self::VersionMismatch { found } => write!(
+                f,
+                "protocol version mismatch: expected {}, found {}",
+                PROTOCOL_VERSION, found
+            ),
+            Self::UnknownCompression(tag) => write!(f, "unknown compression {}", tag),
+            Self::TooLarge(len) => write!(f, "payload expands to {} bytes", len),
+            Self::Decompress(e) => write!(f, "corrupted compressed payload: {}", e),
+            Self::Malformed(e) => write!(f, "malformed payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decompress(e) => Some(e),
+            Self::Malformed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Position as fractions of the [`map::BOUNDS_MIN`] to [`map::BOUNDS_MAX`] box, 16 bits per axis.
+///
+/// Off by at most half a millimetre inside the bounds, positions outside saturate at their edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub struct QuantizedPosition([u16; 3]);
+
+impl QuantizedPosition {
+    pub fn new(pos: [f32; 3]) -> Self {
+        let (min, max) = (map::BOUNDS_MIN.to_array(), map::BOUNDS_MAX.to_array());
+
+        Self(std::array::from_fn(|axis| {
+            quantize_range(pos[axis], min[axis], max[axis])
+        }))
+    }
+
+    pub fn get(self) -> [f32; 3] {
+        let (min, max) = (map::BOUNDS_MIN.to_array(), map::BOUNDS_MAX.to_array());
+
+        std::array::from_fn(|axis| dequantize_range(self.0[axis], min[axis], max[axis]))
+    }
+}
+
+/// Velocity in 16 bit fixed point over `-range..=range` per axis, off by at most `range / 65000`:
+/// half a step and some `f32` rounding.
+///
+/// The range is not sent, both sides pick it by entity kind, see [`PLAYER_VELOCITY_RANGE`] and
+/// [`PROJECTILE_VELOCITY_RANGE`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub struct QuantizedVelocity([i16; 3]);
+
+impl QuantizedVelocity {
+    pub fn new(vel: [f32; 3], range: f32) -> Self {
+        Self(vel.map(|v| {
+            (v / range * i16::MAX as f32)
+                .round()
+                .clamp(-i16::MAX as f32, i16::MAX as f32) as i16
+        }))
+    }
+
+    pub fn get(self, range: f32) -> [f32; 3] {
+        self.0.map(|v| v as f32 / i16::MAX as f32 * range)
+    }
+}
+
+/// Camera angles in 16 bits each.
+///
+/// Yaw wraps around the full turn and is off by at most 0.003°, pitch and roll span the ranges
+/// the server clamps them to and are off by less.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub struct QuantizedCamera {
+    yaw: u16,
+    pitch: u16,
+    roll: u16,
+}
+
+impl QuantizedCamera {
+    /// One step of yaw, a full turn split in 2^16.
+    const YAW_STEP: f32 = TAU / 65536.0;
+
+    pub fn new(camera: &CameraInput) -> Self {
+        Self {
+            // A full turn rounds to 65536, which wraps back to 0.
+            yaw: ((camera.yaw.rem_euclid(TAU) / Self::YAW_STEP).round() as u32 % 65536) as u16,
+            pitch: quantize_range(camera.pitch, -MAX_CAMERA_PITCH, MAX_CAMERA_PITCH),
+            roll: quantize_range(camera.roll, -MAX_CAMERA_ROLL, MAX_CAMERA_ROLL),
+        }
+    }
+
+    /// Yaw comes back within `0..TAU`, whatever turn it was sent from.
+    pub fn get(self) -> CameraInput {
+        CameraInput {
+            yaw: self.yaw as f32 * Self::YAW_STEP,
+            pitch: dequantize_range(self.pitch, -MAX_CAMERA_PITCH, MAX_CAMERA_PITCH),
+            roll: dequantize_range(self.roll, -MAX_CAMERA_ROLL, MAX_CAMERA_ROLL),
+        }
+    }
+}
+
+/// A player's movement booleans packed in one byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub struct PlayerFlags(u8);
+
+impl PlayerFlags {
+    const GROUNDED: u8 = 1 << 0;
+    const JUMP_QUEUED: u8 = 1 << 1;
+    const CROUCHED: u8 = 1 << 2;
+
+    pub fn grounded(self) -> bool {
+        self.0 & Self::GROUNDED != 0
+    }
+
+    pub fn jump_queued(self) -> bool {
+        self.0 & Self::JUMP_QUEUED != 0
+    }
+
+    pub fn crouched(self) -> bool {
+        self.0 & Self::CROUCHED != 0
+    }
+}
+
+impl From<&ClientData> for PlayerFlags {
+    fn from(player: &ClientData) -> Self {
+        let flags = [
+            (player.grounded, Self::GROUNDED),
+            (player.jump_queued, Self::JUMP_QUEUED),
+            (player.crouched, Self::CROUCHED),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, bit)| flags | bit);
+
+        Self(flags)
+    }
+}
+
+/// Maps `min..=max` onto the whole `u16` range, off by at most `(max - min) / 128000`: half a step
+/// and some `f32` rounding.
+fn quantize_range(value: f32, min: f32, max: f32) -> u16 {
+    let fraction = ((value - min) / (max - min)).clamp(0.0, 1.0);
+
+    (fraction * u16::MAX as f32).round() as u16
+}
+
+fn dequantize_range(value: u16, min: f32, max: f32) -> f32 {
+    min + value as f32 / u16::MAX as f32 * (max - min)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::{
+        delta::SnapshotDelta, Envelope, GameEvent, PrivatePlayerData, ProjectileData, WeaponKind,
+        WorldSnapshot,
+    };
+
+    /// 0.003°, the documented yaw error.
+    const ANGLE_ERROR: f32 = 0.003 * PI / 180.0;
+    /// A few samples for each of the 65536 steps of a 16 bit field, a prime so they fall at
+    /// different offsets within each step.
+    const SAMPLES: u32 = 200_003;
+
+    /// `n + 1` evenly spaced values from `min` to `max`, both included.
+    fn sweep(min: f32, max: f32, n: u32) -> impl Iterator<Item = f32> {
+        (0..=n).map(move |i| min + (max - min) * (i as f64 / n as f64) as f32)
+    }
+
+    /// Angle between two yaws, the short way around.
+    fn yaw_error(a: f32, b: f32) -> f32 {
+        let difference = (a - b).rem_euclid(TAU);
+        difference.min(TAU - difference)
+    }
+
+    #[test]
+    fn position_within_half_a_millimetre() {
+        let (min, max) = (map::BOUNDS_MIN.to_array(), map::BOUNDS_MAX.to_array());
+
+        for axis in 0..3 {
+            for value in sweep(min[axis], max[axis], SAMPLES) {
+                let mut pos = [0.0; 3];
+                pos[axis] = value;
+
+                let error = (QuantizedPosition::new(pos).get()[axis] - value).abs();
+                assert!(error <= 0.0005, "{value} on axis {axis} off by {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn position_saturates_at_bounds() {
+        assert_eq!(
+            QuantizedPosition::new([-32.0, -16.0, -32.0]).get(),
+            [-32.0, -16.0, -32.0]
+        );
+        assert_eq!(
+            QuantizedPosition::new([32.0, 48.0, 32.0]).get(),
+            [32.0, 48.0, 32.0]
+        );
+        assert_eq!(
+            QuantizedPosition::new([-100.0, 100.0, f32::MAX]).get(),
+            [-32.0, 48.0, 32.0]
+        );
+    }
+
+    #[test]
+    fn velocity_within_range_over_65000() {
+        for range in [PLAYER_VELOCITY_RANGE, PROJECTILE_VELOCITY_RANGE] {
+            for value in sweep(-range, range, SAMPLES) {
+                let vel = QuantizedVelocity::new([value, -value, 0.0], range).get(range);
+
+                for (received, sent) in vel.into_iter().zip([value, -value, 0.0]) {
+                    let error = (received - sent).abs();
+                    assert!(
+                        error <= range / 65000.0,
+                        "{sent} in range {range} off by {error}"
+                    );
+                }
+            }
+
+            assert_eq!(
+                QuantizedVelocity::new([range * 2.0, -range * 2.0, 0.0], range).get(range),
+                [range, -range, 0.0]
+            );
+        }
+    }
+
+    #[test]
+    fn camera_within_three_thousandths_of_a_degree() {
+        for pitch in sweep(-MAX_CAMERA_PITCH, MAX_CAMERA_PITCH, SAMPLES) {
+            for roll in [-MAX_CAMERA_ROLL, 0.0, MAX_CAMERA_ROLL] {
+                let sent = CameraInput {
+                    pitch,
+                    yaw: 0.0,
+                    roll,
+                };
+                let received = QuantizedCamera::new(&sent).get();
+
+                assert!(
+                    (received.pitch - pitch).abs() < ANGLE_ERROR,
+                    "pitch {pitch}"
+                );
+                assert!((received.roll - roll).abs() < ANGLE_ERROR, "roll {roll}");
+            }
+        }
+
+        for roll in sweep(-MAX_CAMERA_ROLL, MAX_CAMERA_ROLL, SAMPLES) {
+            let sent = CameraInput {
+                roll,
+                ..Default::default()
+            };
+
+            let error = (QuantizedCamera::new(&sent).get().roll - roll).abs();
+            assert!(error < ANGLE_ERROR, "roll {roll} off by {error}");
+        }
+    }
+
+    #[test]
+    fn camera_clamps_pitch_and_roll() {
+        let received = QuantizedCamera::new(&CameraInput {
+            pitch: 2.0,
+            yaw: 0.0,
+            roll: -1.0,
+        })
+        .get();
+
+        assert_eq!(received.pitch, MAX_CAMERA_PITCH);
+        assert_eq!(received.roll, -MAX_CAMERA_ROLL);
+    }
+
+    #[test]
+    fn yaw_wraps_around() {
+        let edges = [
+            PI,
+            -PI,
+            PI - 1e-6,
+            -PI + 1e-6,
+            0.0,
+            -1e-7,
+            TAU - 1e-7,
+            TAU,
+            -TAU,
+            PI + 3.0 * TAU,
+            -PI - 5.0 * TAU,
+        ];
+
+        for yaw in edges
+            .into_iter()
+            .chain(sweep(-4.0 * TAU, 4.0 * TAU, 8 * SAMPLES))
+        {
+            let received = QuantizedCamera::new(&CameraInput {
+                yaw,
+                ..Default::default()
+            })
+            .get();
+
+            assert!((0.0..TAU).contains(&received.yaw), "yaw {yaw}");
+            let error = yaw_error(received.yaw, yaw);
+            assert!(error <= ANGLE_ERROR, "yaw {yaw} off by {error}");
+        }
+    }
+
+    #[test]
+    fn player_flags_round_trip() {
+        for bits in 0..8 {
+            let player = ClientData {
+                id: 0,
+                pos: [0.0; 3],
+                vel: [0.0; 3],
+                rot: CameraInput::default(),
+                grounded: bits & 1 != 0,
+                jump_queued: bits & 2 != 0,
+                crouched: bits & 4 != 0,
+            };
+            let flags = PlayerFlags::from(&player);
+
+            assert_eq!(flags.grounded(), player.grounded);
+            assert_eq!(flags.jump_queued(), player.jump_queued);
+            assert_eq!(flags.crouched(), player.crouched);
+        }
+    }
+
+    /// A round in progress, everyone moving so that consecutive ticks differ.
+    fn world(tick: u32, players: u64, projectiles: u64) -> WorldSnapshot {
+        let t = tick as f32 / 64.0;
+        let spread = |i: u64, speed: f32| {
+            let angle = i as f32 * 2.4;
+            let vel = [angle.cos() * speed, 0.0, angle.sin() * speed];
+            let pos = [
+                (angle.sin() * 24.0 + vel[0] * t).clamp(-32.0, 32.0),
+                (i % 3) as f32 * 4.0 + 1.0,
+                (angle.cos() * 24.0 + vel[2] * t).clamp(-32.0, 32.0),
+            ];
+            (pos, vel)
+        };
+
+        WorldSnapshot {
+            tick,
+            players: (0..players)
+                .map(|i| {
+                    let (pos, vel) = spread(i, 6.0);
+                    ClientData {
+                        id: i,
+                        pos,
+                        vel,
+                        rot: CameraInput {
+                            yaw: (i as f32 + t).rem_euclid(TAU),
+                            pitch: (i as f32 * 0.7).sin() * 0.5,
+                            ..Default::default()
+                        },
+                        grounded: i % 4 != 0,
+                        jump_queued: false,
+                        crouched: i % 5 == 0,
+                    }
+                })
+                .collect(),
+            projectiles: (0..projectiles)
+                .map(|i| {
+                    let (pos, vel) = spread(i + 100, 300.0);
+                    ProjectileData { id: i, pos, vel }
+                })
+                .collect(),
+            private: Some(PrivatePlayerData {
+                reload: None,
+                fire_cooldown: 0.0,
+                buffered_inputs: 2,
+                last_input_sequence: tick,
+            }),
+        }
+    }
+
+    fn lz4_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        frame.push(Compression::Lz4.tag());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let text = "snapshot ".repeat(200);
+        let frame = encode_with(&text, Compression::Lz4);
+
+        assert_eq!(frame[HEADER_LEN - 1], Compression::Lz4.tag());
+        assert!(frame.len() < encode(&text).len());
+        assert_eq!(decode::<String>(&frame).unwrap(), text);
+
+        let snapshot = SnapshotDelta::encode(None, &world(0, 16, 8));
+        let frame = encode_with(&Envelope::Snapshot(snapshot), Compression::Lz4);
+
+        assert_eq!(frame[HEADER_LEN - 1], Compression::Lz4.tag());
+        match decode::<Envelope>(&frame).unwrap() {
+            Envelope::Snapshot(snapshot) => {
+                assert_eq!(snapshot.players.len(), 16);
+                assert_eq!(snapshot.projectiles.len(), 8);
+            }
+            other => panic!("decoded {other:?}"),
+        }
+    }
+
+    #[test]
+    fn small_payloads_sent_uncompressed() {
+        let text = "snapshot ".repeat(2);
+        let frame = encode_with(&text, Compression::Lz4);
+
+        assert!(to_payload(&text).len() < COMPRESSION_THRESHOLD);
+        assert_eq!(frame[HEADER_LEN - 1], Compression::None.tag());
+        assert_eq!(decode::<String>(&frame).unwrap(), text);
+    }
+
+    #[test]
+    fn incompressible_payloads_sent_uncompressed() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let frame = encode_with(&noise, Compression::Lz4);
+
+        assert_eq!(frame[HEADER_LEN - 1], Compression::None.tag());
+        assert_eq!(decode::<Vec<u8>>(&frame).unwrap(), noise);
+    }
+
+    #[test]
+    fn rejects_oversized_claims() {
+        let claimed = MAX_DECOMPRESSED_LEN + 1;
+        let mut payload = (claimed as u32).to_le_bytes().to_vec();
+        payload.extend_from_slice(&[0; 16]);
+
+        assert!(matches!(
+            decode::<String>(&lz4_frame(&payload)),
+            Err(DecodeError::TooLarge(len)) if len == claimed
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupted_compression() {
+        let mut payload = lz4_flex::compress_prepend_size("snapshot ".repeat(200).as_bytes());
+        payload.truncate(payload.len() / 2);
+
+        assert!(matches!(
+            decode::<String>(&lz4_frame(&payload)),
+            Err(DecodeError::Decompress(_))
+        ));
+        assert!(matches!(
+            decode::<String>(&lz4_frame(&[1, 0])),
+            Err(DecodeError::Truncated)
+        ));
+    }
+
+    /// Typical messages below [`COMPRESSION_THRESHOLD`] go out as is, larger ones come out
+    /// smaller. The `compression` bench measures by how much and at what cost.
+    #[test]
+    fn compresses_typical_messages_above_threshold() {
+        let full = |players, projectiles| {
+            Envelope::Snapshot(SnapshotDelta::encode(None, &world(0, players, projectiles)))
+        };
+        let delta = |players, projectiles| {
+            Envelope::Snapshot(SnapshotDelta::encode(
+                Some(&world(0, players, projectiles)),
+                &world(4, players, projectiles),
+            ))
+        };
+        let events = Envelope::Events {
+            tick: 1,
+            events: vec![
+                GameEvent::ShotFired {
+                    projectile_id: 7,
+                    shooter: 1,
+                    weapon: WeaponKind::Rifle,
+                    pos: [3.0, 1.6, -12.0],
+                },
+                GameEvent::PlayerDamaged {
+                    id: 2,
+                    attacker: 1,
+                    amount: 25.0,
+                },
+            ],
+        };
+
+        for small in [events, full(1, 0), delta(1, 1)] {
+            assert!(
+                to_payload(&small).len() < COMPRESSION_THRESHOLD,
+                "{small:?}"
+            );
+
+            let frame = encode_with(&small, Compression::Lz4);
+            assert_eq!(frame[HEADER_LEN - 1], Compression::None.tag());
+        }
+
+        for large in [full(8, 4), full(32, 16), delta(8, 4), delta(32, 16)] {
+            assert!(to_payload(&large).len() >= COMPRESSION_THRESHOLD);
+
+            let frame = encode_with(&large, Compression::Lz4);
+            assert_eq!(frame[HEADER_LEN - 1], Compression::Lz4.tag());
+            assert!(frame.len() < encode(&large).len());
+        }
+    }
 }
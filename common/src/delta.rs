@@ -0,0 +1,467 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::Resource;
+use bevy_renet2::prelude::ClientId;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::{
+    data::{
+        PlayerFlags, QuantizedCamera, QuantizedPosition, QuantizedVelocity, PLAYER_VELOCITY_RANGE,
+        PROJECTILE_VELOCITY_RANGE,
+    },
+    *,
+};
+
+/// How many snapshots are kept around to be used as delta baselines.
+///
+/// Acknowledgements older than this fall back to a full snapshot.
+pub const SNAPSHOT_HISTORY_LEN: usize = 64;
+
+/// Recent full snapshots, indexed by tick.
+#[derive(Debug, Default, Resource)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<WorldSnapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn get(&self, tick: u32) -> Option<&WorldSnapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn latest(&self) -> Option<&WorldSnapshot> {
+        self.snapshots.back()
+    }
+
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        if self.snapshots.len() == SNAPSHOT_HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+}
+
+/// A [`WorldSnapshot`] encoded relative to a baseline the receiver already acknowledged.
+///
+/// Without a baseline every entity is sent with all of its fields.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    pub baseline_tick: Option<u32>,
+    pub players: Vec<ClientDataDelta>,
+    pub removed_players: Vec<ClientId>,
+    pub projectiles: Vec<ProjectileDataDelta>,
+    pub removed_projectiles: Vec<u64>,
+    pub private: PrivateDelta,
+}
+
+impl SnapshotDelta {
+    pub fn encode(baseline: Option<&WorldSnapshot>, snapshot: &WorldSnapshot) -> Self {
+        let base_players = baseline.map_or(&[][..], |b| b.players.as_slice());
+        let base_projectiles = baseline.map_or(&[][..], |b| b.projectiles.as_slice());
+
+        Self {
+            tick: snapshot.tick,
+            baseline_tick: baseline.map(|b| b.tick),
+            players: snapshot
+                .players
+                .iter()
+                .filter_map(|player| {
+                    let old = base_players.iter().find(|old| old.id == player.id);
+                    ClientDataDelta::between(old, player)
+                })
+                .collect(),
+            removed_players: removed(base_players, &snapshot.players, |p| p.id),
+            projectiles: snapshot
+                .projectiles
+                .iter()
+                .filter_map(|projectile| {
+                    let old = base_projectiles.iter().find(|old| old.id == projectile.id);
+                    ProjectileDataDelta::between(old, projectile)
+                })
+                .collect(),
+            removed_projectiles: removed(base_projectiles, &snapshot.projectiles, |p| p.id),
+            private: PrivateDelta::between(
+                baseline.and_then(|b| b.private.as_ref()),
+                snapshot.private.as_ref(),
+            ),
+        }
+    }
+
+    /// Rebuilds the full snapshot, or `None` if the delta does not fit the given baseline.
+    pub fn apply(self, baseline: Option<&WorldSnapshot>) -> Option<WorldSnapshot> {
+        if self.baseline_tick != baseline.map(|b| b.tick) {
+            return None;
+        }
+
+        let base_players = baseline.map_or(&[][..], |b| b.players.as_slice());
+        let base_projectiles = baseline.map_or(&[][..], |b| b.projectiles.as_slice());
+
+        let mut players: Vec<ClientData> = base_players
+            .iter()
+            .filter(|p| !self.removed_players.contains(&p.id))
+            .cloned()
+            .collect();
+
+        for delta in self.players {
+            match players.iter_mut().find(|p| p.id == delta.id) {
+                Some(player) => *player = delta.apply(Some(&*player))?,
+                None => players.push(delta.apply(None)?),
+            }
+        }
+
+        let mut projectiles: Vec<ProjectileData> = base_projectiles
+            .iter()
+            .filter(|p| !self.removed_projectiles.contains(&p.id))
+            .cloned()
+            .collect();
+
+        for delta in self.projectiles {
+            match projectiles.iter_mut().find(|p| p.id == delta.id) {
+                Some(projectile) => *projectile = delta.apply(Some(&*projectile))?,
+                None => projectiles.push(delta.apply(None)?),
+            }
+        }
+
+        Some(WorldSnapshot {
+            tick: self.tick,
+            players,
+            projectiles,
+            private: self
+                .private
+                .apply(baseline.and_then(|b| b.private.as_ref())),
+        })
+    }
+}
+
+/// What became of the [`PrivatePlayerData`] since the baseline.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub enum PrivateDelta {
+    /// Same as in the baseline, including absent from both.
+    Unchanged,
+    /// Present in the baseline but not anymore.
+    Removed,
+    Changed(PrivatePlayerData),
+}
+
+impl PrivateDelta {
+    pub fn between(old: Option<&PrivatePlayerData>, new: Option<&PrivatePlayerData>) -> Self {
+        match (old, new) {
+            (old, Some(new)) if old != Some(new) => Self::Changed(new.clone()),
+            (Some(_), None) => Self::Removed,
+            _ => Self::Unchanged,
+        }
+    }
+
+    fn apply(self, old: Option<&PrivatePlayerData>) -> Option<PrivatePlayerData> {
+        match self {
+            Self::Unchanged => old.cloned(),
+            Self::Removed => None,
+            Self::Changed(new) => Some(new),
+        }
+    }
+}
+
+/// Per-field delta of a [`ClientData`], `None` fields are unchanged from the baseline.
+///
+/// Fields travel quantized and are compared that way, a change too small to show is not sent.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub struct ClientDataDelta {
+    pub id: ClientId,
+    pub pos: Option<QuantizedPosition>,
+    pub vel: Option<QuantizedVelocity>,
+    pub rot: Option<QuantizedCamera>,
+    pub flags: Option<PlayerFlags>,
+}
+
+impl ClientDataDelta {
+    /// `None` when nothing changed.
+    pub fn between(old: Option<&ClientData>, new: &ClientData) -> Option<Self> {
+        let position = |p: &ClientData| QuantizedPosition::new(p.pos);
+        let velocity = |p: &ClientData| QuantizedVelocity::new(p.vel, PLAYER_VELOCITY_RANGE);
+        let rotation = |p: &ClientData| QuantizedCamera::new(&p.rot);
+
+        let delta = Self {
+            id: new.id,
+            pos: changed(old.map(position).as_ref(), &position(new)),
+            vel: changed(old.map(velocity).as_ref(), &velocity(new)),
+            rot: changed(old.map(rotation).as_ref(), &rotation(new)),
+            flags: changed(old.map(PlayerFlags::from).as_ref(), &PlayerFlags::from(new)),
+        };
+
+        let unchanged = delta.pos.is_none()
+            && delta.vel.is_none()
+            && delta.rot.is_none()
+            && delta.flags.is_none();
+
+        (!unchanged).then_some(delta)
+    }
+
+    fn apply(self, old: Option<&ClientData>) -> Option<ClientData> {
+        let (grounded, jump_queued, crouched) = match self.flags {
+            Some(flags) => (flags.grounded(), flags.jump_queued(), flags.crouched()),
+            None => old.map(|o| (o.grounded, o.jump_queued, o.crouched))?,
+        };
+
+        Some(ClientData {
+            id: self.id,
+            pos: self
+                .pos
+                .map(QuantizedPosition::get)
+                .or(old.map(|o| o.pos))?,
+            vel: self
+                .vel
+                .map(|vel| vel.get(PLAYER_VELOCITY_RANGE))
+                .or(old.map(|o| o.vel))?,
+            rot: self.rot.map(QuantizedCamera::get).or(old.map(|o| o.rot))?,
+            grounded,
+            jump_queued,
+            crouched,
+        })
+    }
+}
+
+/// Per-field delta of a [`ProjectileData`], `None` fields are unchanged from the baseline.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub struct ProjectileDataDelta {
+    pub id: u64,
+    pub pos: Option<QuantizedPosition>,
+    pub vel: Option<QuantizedVelocity>,
+}
+
+impl ProjectileDataDelta {
+    /// `None` when nothing changed.
+    pub fn between(old: Option<&ProjectileData>, new: &ProjectileData) -> Option<Self> {
+        let position = |p: &ProjectileData| QuantizedPosition::new(p.pos);
+        let velocity =
+            |p: &ProjectileData| QuantizedVelocity::new(p.vel, PROJECTILE_VELOCITY_RANGE);
+
+        let delta = Self {
+            id: new.id,
+            pos: changed(old.map(position).as_ref(), &position(new)),
+            vel: changed(old.map(velocity).as_ref(), &velocity(new)),
+        };
+
+        (delta.pos.is_some() || delta.vel.is_some()).then_some(delta)
+    }
+
+    fn apply(self, old: Option<&ProjectileData>) -> Option<ProjectileData> {
+        Some(ProjectileData {
+            id: self.id,
+            pos: self
+                .pos
+                .map(QuantizedPosition::get)
+                .or(old.map(|o| o.pos))?,
+            vel: self
+                .vel
+                .map(|vel| vel.get(PROJECTILE_VELOCITY_RANGE))
+                .or(old.map(|o| o.vel))?,
+        })
+    }
+}
+
+fn changed<T: PartialEq + Clone>(old: Option<&T>, new: &T) -> Option<T> {
+    match old {
+        Some(old) if old == new => None,
+        _ => Some(new.clone()),
+    }
+}
+
+fn removed<T, K: PartialEq>(baseline: &[T], current: &[T], key: impl Fn(&T) -> K) -> Vec<K> {
+    baseline
+        .iter()
+        .map(&key)
+        .filter(|k| !current.iter().any(|c| key(c) == *k))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(id: ClientId, x: f32) -> ClientData {
+        ClientData {
+            id,
+            pos: [x, 1.0, -4.0],
+            vel: [2.5, 0.0, -1.0],
+            rot: CameraInput {
+                yaw: 1.0,
+                pitch: -0.2,
+                ..Default::default()
+            },
+            grounded: true,
+            jump_queued: false,
+            crouched: id == 2,
+        }
+    }
+
+    fn projectile(id: u64, x: f32) -> ProjectileData {
+        ProjectileData {
+            id,
+            pos: [x, 2.0, 3.0],
+            vel: [300.0, -9.0, 0.0],
+        }
+    }
+
+    fn private(fire_cooldown: f32) -> PrivatePlayerData {
+        PrivatePlayerData {
+            reload: None,
+            fire_cooldown,
+            buffered_inputs: 2,
+            last_input_sequence: 40,
+        }
+    }
+
+    fn world(tick: u32, players: &[(ClientId, f32)], projectiles: &[(u64, f32)]) -> WorldSnapshot {
+        WorldSnapshot {
+            tick,
+            players: players.iter().map(|&(id, x)| player(id, x)).collect(),
+            projectiles: projectiles
+                .iter()
+                .map(|&(id, x)| projectile(id, x))
+                .collect(),
+            private: Some(private(0.0)),
+        }
+    }
+
+    /// Same as `expected` up to quantization, so a delta between them would be empty.
+    fn assert_received(received: &WorldSnapshot, expected: &WorldSnapshot) {
+        assert_eq!(received.tick, expected.tick);
+        assert_eq!(received.players.len(), expected.players.len());
+        assert_eq!(received.projectiles.len(), expected.projectiles.len());
+        assert_eq!(received.private, expected.private);
+
+        for player in &expected.players {
+            let received = received.players.iter().find(|p| p.id == player.id);
+            assert!(received.is_some(), "player {} missing", player.id);
+            assert!(ClientDataDelta::between(received, player).is_none());
+        }
+        for projectile in &expected.projectiles {
+            let received = received.projectiles.iter().find(|p| p.id == projectile.id);
+            assert!(received.is_some(), "projectile {} missing", projectile.id);
+            assert!(ProjectileDataDelta::between(received, projectile).is_none());
+        }
+    }
+
+    #[test]
+    fn full_snapshot_round_trip() {
+        let snapshot = world(7, &[(1, 0.0), (2, 5.0)], &[(9, -3.0)]);
+        let delta = SnapshotDelta::encode(None, &snapshot);
+
+        assert_eq!(delta.baseline_tick, None);
+        assert_eq!(delta.players.len(), 2);
+        assert_eq!(delta.projectiles.len(), 1);
+        assert_received(&delta.apply(None).unwrap(), &snapshot);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let baseline = SnapshotDelta::encode(None, &world(7, &[(1, 0.0), (2, 5.0)], &[(9, -3.0)]))
+            .apply(None)
+            .unwrap();
+        let mut snapshot = world(9, &[(1, 0.5), (2, 5.0), (3, 8.0)], &[(9, -1.0)]);
+        snapshot.players[1].jump_queued = true;
+        snapshot.private = Some(private(0.25));
+
+        let delta = SnapshotDelta::encode(Some(&baseline), &snapshot);
+
+        assert_eq!(delta.baseline_tick, Some(7));
+        assert_eq!(delta.players.len(), 3);
+        assert!(matches!(delta.private, PrivateDelta::Changed(_)));
+        assert_received(&delta.apply(Some(&baseline)).unwrap(), &snapshot);
+    }
+
+    #[test]
+    fn rejects_other_baselines() {
+        let baseline = world(7, &[(1, 0.0)], &[]);
+        let snapshot = world(9, &[(1, 0.5)], &[]);
+
+        let delta = || SnapshotDelta::encode(Some(&baseline), &snapshot);
+        assert!(delta().apply(None).is_none());
+        assert!(delta().apply(Some(&world(8, &[(1, 0.0)], &[]))).is_none());
+
+        let full = SnapshotDelta::encode(None, &snapshot);
+        assert!(full.apply(Some(&baseline)).is_none());
+    }
+
+    #[test]
+    fn rejects_a_new_player_sent_in_part() {
+        let baseline = world(7, &[(1, 0.0)], &[]);
+        let mut delta = SnapshotDelta::encode(Some(&baseline), &world(9, &[(1, 0.5)], &[]));
+        delta.players[0].id = 2;
+
+        assert!(delta.apply(Some(&baseline)).is_none());
+    }
+
+    #[test]
+    fn removes_players_and_projectiles() {
+        let baseline = world(7, &[(1, 0.0), (2, 5.0)], &[(9, -3.0), (10, 4.0)]);
+        let snapshot = world(9, &[(2, 5.0)], &[(10, 4.0)]);
+
+        let delta = SnapshotDelta::encode(Some(&baseline), &snapshot);
+
+        assert_eq!(delta.removed_players, [1]);
+        assert_eq!(delta.removed_projectiles, [9]);
+        assert_received(&delta.apply(Some(&baseline)).unwrap(), &snapshot);
+    }
+
+    #[test]
+    fn changes_too_small_to_quantize_are_not_sent() {
+        // As received, every field sits on a quantization step.
+        let baseline = SnapshotDelta::encode(None, &world(7, &[(1, 0.0)], &[(9, -3.0)]))
+            .apply(None)
+            .unwrap();
+        let nudge = |v: [f32; 3]| v.map(|v| v + 0.0001);
+        let mut player = baseline.players[0].clone();
+        player.pos = nudge(player.pos);
+        player.vel = nudge(player.vel);
+        player.rot.yaw += 0.00001;
+        let mut projectile = baseline.projectiles[0].clone();
+        projectile.pos = nudge(projectile.pos);
+        projectile.vel = nudge(projectile.vel);
+        let snapshot = WorldSnapshot {
+            tick: 9,
+            players: vec![player],
+            projectiles: vec![projectile],
+            private: baseline.private.clone(),
+        };
+
+        let delta = SnapshotDelta::encode(Some(&baseline), &snapshot);
+
+        assert!(delta.players.is_empty());
+        assert!(delta.projectiles.is_empty());
+        assert!(matches!(delta.private, PrivateDelta::Unchanged));
+
+        let received = delta.apply(Some(&baseline)).unwrap();
+        assert_eq!(received.players[0].pos, baseline.players[0].pos);
+        assert_eq!(received.projectiles[0].pos, baseline.projectiles[0].pos);
+    }
+
+    #[test]
+    fn only_changed_fields_are_sent() {
+        let baseline = world(7, &[(1, 0.0)], &[]);
+        let mut snapshot = world(9, &[(1, 0.0)], &[]);
+        snapshot.players[0].crouched = true;
+
+        let delta = SnapshotDelta::encode(Some(&baseline), &snapshot);
+        let player = &delta.players[0];
+
+        assert!(player.flags.is_some());
+        assert!(player.pos.is_none() && player.vel.is_none() && player.rot.is_none());
+    }
+
+    #[test]
+    fn private_state_can_be_removed() {
+        let baseline = world(7, &[(1, 0.0)], &[]);
+        let mut snapshot = world(9, &[(1, 0.0)], &[]);
+        snapshot.private = None;
+
+        let delta = SnapshotDelta::encode(Some(&baseline), &snapshot);
+
+        assert!(matches!(delta.private, PrivateDelta::Removed));
+        assert_eq!(delta.apply(Some(&baseline)).unwrap().private, None);
+
+        let absent = SnapshotDelta::encode(Some(&snapshot), &world(11, &[(1, 0.0)], &[]));
+        assert!(matches!(absent.private, PrivateDelta::Changed(_)));
+    }
+}
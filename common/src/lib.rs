@@ -1,12 +1,29 @@
+pub mod auth;
 pub mod data;
+pub mod delta;
+pub mod map;
+pub mod movement;
+pub mod net_sim;
+pub mod replication;
 
 use bevy::{platform::collections::HashMap, prelude::*};
 use bevy_rapier3d::prelude::*;
-use bevy_renet2::{netcode::NetcodeTransportError, prelude::ClientId};
+use bevy_renet2::prelude::ClientId;
+use replication::{AppReplicationExt, Audience, Replicated};
 use rkyv::{Archive, Deserialize, Serialize};
 
 pub const DEFAULT_PORT: u16 = 9080;
+/// Netcode protocol id, kept stable on purpose: netcode silently drops mismatching clients,
+/// so incompatibilities are detected by the handshake instead (see [`PROTOCOL_VERSION`]).
 pub const PROTOCOL_ID: u64 = 0;
+/// Bumped whenever the layout of any [`Envelope`] payload changes.
+pub const PROTOCOL_VERSION: u16 = 14;
+/// Git revision this binary was built from.
+pub const BUILD_HASH: &str = env!("BUILD_HASH");
+/// Default simulation rate, servers may run at another one and announce it on join.
+pub const TICK_RATE: f64 = 128.0;
+/// How many of the latest inputs every input packet repeats, covering that many lost packets.
+pub const INPUT_REDUNDANCY: usize = 8;
 pub const PLAYER_COLLIDER_RADIUS: f32 = 0.35;
 pub const PLAYER_COLLIDER_HALF_HEIGHT: f32 = 0.55;
 pub const PLAYER_CROUCH_COLLIDER_HALF_HEIGHT: f32 = 0.25;
@@ -28,40 +45,89 @@ pub const PLAYER_MAX_HEALTH: f32 = 100.0;
 pub const PROJECTILE_LIFETIME: f32 = 3.0;
 pub const PROJECTILE_GRAVITY: f32 = 9.81;
 pub const PLAYER_RESPAWN_HEIGHT: f32 = 1.5;
+/// Bullet holes kept in the world, the oldest one goes when a new one would exceed this.
+pub const MAX_IMPACT_MARKS: usize = 256;
+/// Furthest the camera pitches up or down, just short of straight up where yaw flips.
+pub const MAX_CAMERA_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+/// Camera roll while leaning.
+pub const MAX_CAMERA_ROLL: f32 = 0.3;
 
 pub struct Plugin;
 
 impl bevy::prelude::Plugin for Plugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.init_resource::<Lobby>()
+            .init_resource::<TickRate>()
+            .replicate::<ImpactMark>()
+            .replicate::<Client>()
+            .replicate::<PlayerVisualState>()
+            .replicate::<PlayerStatus>()
             .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule())
-            .add_systems(Update, panic_on_error_system);
+            .add_systems(Startup, map::spawn_world_colliders);
     }
 }
 
-fn panic_on_error_system(mut renet_error: MessageReader<NetcodeTransportError>) {
-    if let Some(e) = renet_error.read().next() {
-        panic!("{}", e);
+#[derive(Debug, Archive, Serialize, Deserialize, Component, Resource)]
+pub struct PlayerId(pub u64);
+
+/// Simulation ticks per second of the server we are playing on.
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct TickRate(pub f64);
+
+impl Default for TickRate {
+    fn default() -> Self {
+        Self(TICK_RATE)
     }
 }
 
-#[derive(Debug, Archive, Serialize, Deserialize, Component, Resource)]
-pub struct PlayerId(pub u64);
+impl TickRate {
+    /// Seconds simulated per tick, which the client keeps even while its fixed timestep drifts.
+    pub fn delta(&self) -> f32 {
+        (1.0 / self.0) as f32
+    }
+}
 
-#[derive(Debug, Archive, Serialize, Deserialize)]
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
 pub struct ClientData {
     pub id: ClientId,
     pub pos: [f32; 3],
+    pub vel: [f32; 3],
     pub rot: CameraInput,
+    pub grounded: bool,
+    pub jump_queued: bool,
     pub crouched: bool,
-    pub alive: bool,
-    pub health: f32,
+}
+
+/// Per-tick state only the owning player is sent, opponents never learn it.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize)]
+pub struct PrivatePlayerData {
+    pub reload: Option<ReloadProgress>,
+    /// Seconds until the active weapon can fire again.
+    pub fire_cooldown: f32,
+    /// Inputs the server had buffered ahead of the tick it just simulated, the client runs its
+    /// ticks faster or slower to keep a few there.
+    pub buffered_inputs: u8,
+    /// Sequence of the newest input the server simulated this player with, the client replays
+    /// the ones after it.
+    pub last_input_sequence: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+pub struct ReloadProgress {
     pub weapon: WeaponKind,
-    pub ammo_in_mag: u32,
+    /// Seconds until the magazine is full.
+    pub remaining: f32,
 }
 
-#[derive(Debug, Default, Archive, Serialize, Deserialize, Component, Resource)]
+#[derive(Debug, Default, Clone, Archive, Serialize, Deserialize, Component, Resource)]
 pub struct ClientInput {
+    /// Client fixed tick this input was sampled on, increases by one every tick.
+    pub sequence: u32,
+    /// Server tick of the remote state the client was rendering when sampling this input, none
+    /// before it rendered any.
+    pub view_tick: Option<u32>,
+    /// Latest snapshot tick received, used by the server as the next delta baseline.
+    pub snapshot_ack: u32,
     pub forward: bool,
     pub backward: bool,
     pub left: bool,
@@ -85,6 +151,16 @@ pub enum WeaponKind {
 }
 
 impl WeaponKind {
+    pub const ALL: [Self; 2] = [Self::Rifle, Self::Pistol];
+
+    /// Position in [`WeaponKind::ALL`], used to index per-weapon arrays.
+    pub fn index(self) -> usize {
+        match self {
+            Self::Rifle => 0,
+            Self::Pistol => 1,
+        }
+    }
+
     pub fn spec(self) -> WeaponSpec {
         match self {
             Self::Rifle => WeaponSpec {
@@ -141,7 +217,7 @@ impl WeaponSpec {
     }
 }
 
-#[derive(Debug, Default, Archive, Serialize, Deserialize, Component)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Archive, Serialize, Deserialize, Component)]
 pub struct CameraInput {
     pub pitch: f32,
     pub yaw: f32,
@@ -165,11 +241,14 @@ impl From<Quat> for CameraInput {
     }
 }
 
-#[derive(Debug, Component)]
+/// A player, replicated to everyone for as long as it is connected.
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, Component)]
 pub struct Client {
     pub id: ClientId,
 }
 
+impl Replicated for Client {}
+
 #[derive(Debug, Default, Component)]
 pub struct MovementState {
     pub velocity: Vec3,
@@ -178,41 +257,66 @@ pub struct MovementState {
     pub crouched: bool,
 }
 
-#[derive(Debug, Default, Component)]
+/// How a player looks, replicated to the clients that may see it.
+#[derive(Debug, Default, Clone, PartialEq, Archive, Serialize, Deserialize, Component)]
 pub struct PlayerVisualState {
     pub alive: bool,
     pub crouched: bool,
-    pub health: f32,
     pub weapon: WeaponKind,
-    pub ammo_in_mag: u32,
 }
 
-#[derive(Debug, Archive, Serialize, Deserialize)]
-pub struct ProjectileData {
-    pub id: u64,
-    pub pos: [f32; 3],
-    pub vel: [f32; 3],
+impl Replicated for PlayerVisualState {
+    const AUDIENCE: Audience = Audience::Interest;
+}
+
+/// Health and ammunition, replicated to the player they belong to only.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize, Component)]
+pub struct PlayerStatus {
+    pub health: f32,
+    /// Rounds left in every magazine, indexed by [`WeaponKind::index`].
+    pub magazines: [u32; 2],
+}
+
+impl PlayerStatus {
+    pub fn ammo(&self, weapon: WeaponKind) -> u32 {
+        self.magazines[weapon.index()]
+    }
+
+    /// Dead, jumping brings the player back.
+    pub fn can_respawn(&self) -> bool {
+        self.health <= 0.0
+    }
+}
+
+impl Replicated for PlayerStatus {
+    const AUDIENCE: Audience = Audience::Owner;
 }
 
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
-pub struct FiredProjectileData {
+pub struct ProjectileData {
     pub id: u64,
-    pub weapon: WeaponKind,
+    pub pos: [f32; 3],
+    pub vel: [f32; 3],
 }
 
-#[derive(Debug, Archive, Serialize, Deserialize, Clone)]
-pub struct ImpactMarkData {
-    pub id: u64,
+/// A bullet hole, replicated to the clients near it.
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, Component)]
+pub struct ImpactMark {
     pub pos: [f32; 3],
     pub normal: [f32; 3],
 }
 
+impl Replicated for ImpactMark {
+    const AUDIENCE: Audience = Audience::Interest;
+}
+
 #[derive(Debug, Archive, Serialize, Deserialize)]
 pub struct WorldSnapshot {
+    pub tick: u32,
     pub players: Vec<ClientData>,
     pub projectiles: Vec<ProjectileData>,
-    pub impact_marks: Vec<ImpactMarkData>,
-    pub fired_projectiles: Vec<FiredProjectileData>,
+    /// Only set in the snapshot sent to the player it belongs to.
+    pub private: Option<PrivatePlayerData>,
 }
 
 #[derive(Debug, Default, Resource)]
@@ -220,8 +324,63 @@ pub struct Lobby {
     pub players: HashMap<ClientId, Entity>,
 }
 
+/// First message a client sends once connected, before any input is accepted.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub struct Hello {
+    pub build_hash: String,
+    /// Compression the client accepts on what the server sends it.
+    pub compression: data::Compression,
+}
+
+/// Wraps every payload sent over the wire.
+#[derive(Debug, Archive, Serialize, Deserialize)]
+pub enum Envelope {
+    Hello(Hello),
+    /// Latest inputs oldest first, see [`INPUT_REDUNDANCY`].
+    Input(Vec<ClientInput>),
+    Server(ServerMessage),
+    Snapshot(delta::SnapshotDelta),
+    Replication(replication::ReplicationMessage),
+    /// Everything that happened on a server tick, sent reliably and in order.
+    Events {
+        tick: u32,
+        events: Vec<GameEvent>,
+    },
+}
+
+/// Something that happened once, as opposed to state that is continuously replicated.
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+pub enum GameEvent {
+    ShotFired {
+        projectile_id: u64,
+        shooter: ClientId,
+        weapon: WeaponKind,
+        /// Muzzle position the shot left from.
+        pos: [f32; 3],
+    },
+    /// Only sent to the victim and the attacker, health is private.
+    PlayerDamaged {
+        id: ClientId,
+        attacker: ClientId,
+        amount: f32,
+    },
+    PlayerKilled {
+        id: ClientId,
+        killer: ClientId,
+    },
+    PlayerRespawned {
+        id: ClientId,
+    },
+}
+
 #[derive(Debug, Archive, Serialize, Deserialize, Component)]
 pub enum ServerMessage {
-    ClientConnected { id: ClientId },
-    ClientDisconnected { id: ClientId },
+    /// First message after a successful handshake.
+    Welcome {
+        tick_rate: f64,
+        map: String,
+    },
+    Kicked {
+        reason: String,
+    },
 }
@@ -0,0 +1,28 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+/// Maps both binaries know how to build, the server picks one and announces it on join.
+pub const MAPS: &[&str] = &[DEFAULT_MAP];
+pub const DEFAULT_MAP: &str = "arena";
+/// Box every map fits in with room to jump and fall, snapshots encode positions relative to it.
+pub const BOUNDS_MIN: Vec3 = Vec3::new(-32.0, -16.0, -32.0);
+pub const BOUNDS_MAX: Vec3 = Vec3::new(32.0, 48.0, 32.0);
+
+/// Outside of it there is nothing left to hit or see.
+pub fn in_bounds(pos: Vec3) -> bool {
+    pos.cmpge(BOUNDS_MIN).all() && pos.cmple(BOUNDS_MAX).all()
+}
+
+pub fn spawn_world_colliders(mut commands: Commands) {
+    commands.spawn(Collider::cuboid(10.0, 0.1, 10.0));
+
+    commands.spawn((
+        Collider::cuboid(1.0, 0.25, 0.5),
+        Transform::from_xyz(0.0, 0.25, -3.0),
+    ));
+
+    commands.spawn((
+        Collider::cuboid(1.0, 0.25, 0.5),
+        Transform::from_xyz(0.75, 1.75, 0.0),
+    ));
+}
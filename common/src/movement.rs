@@ -0,0 +1,207 @@
+use bevy::prelude::*;
+use bevy_rapier3d::{
+    prelude::*,
+    rapier::{control::KinematicCharacterController as CharacterController, math::Pose},
+};
+
+use crate::*;
+
+/// Membership of every player collider, left out of movement queries so the server moves players
+/// against the same colliders client prediction has. Hit detection still sees them.
+pub const PLAYER_COLLISION_GROUP: Group = Group::GROUP_2;
+
+/// Advances a player's movement by one fixed tick.
+///
+/// This is the single source of truth for player movement: the server runs it to simulate
+/// every player and the client runs it to predict (and replay) the local player, so it must
+/// only depend on its arguments and the static world colliders. Other players are walked through,
+/// the client does not have their colliders, see [`PLAYER_COLLISION_GROUP`].
+pub fn step_player_movement(
+    entity: Entity,
+    rapier_context: &RapierContext<'_>,
+    input: &ClientInput,
+    movement: &mut MovementState,
+    collider: &mut Collider,
+    transform: &mut Transform,
+    delta: f32,
+) {
+    let wants_to_crouch = input.crouch;
+
+    if wants_to_crouch && !movement.crouched {
+        set_crouched_state(movement, collider, transform, true);
+    } else if !wants_to_crouch
+        && movement.crouched
+        && can_stand_up(entity, rapier_context, transform.translation)
+    {
+        set_crouched_state(movement, collider, transform, false);
+    }
+
+    let x = (input.right as i8 - input.left as i8) as f32;
+    let z = (input.backward as i8 - input.forward as i8) as f32;
+
+    let local_input = Vec3::new(x, 0.0, z).normalize_or_zero();
+    let yaw_rotation = Quat::from_rotation_y(input.camera.yaw);
+    let wish_dir = yaw_rotation * local_input;
+
+    let horizontal_velocity = Vec3::new(movement.velocity.x, 0.0, movement.velocity.z);
+    let target_speed = if movement.crouched {
+        PLAYER_CROUCH_SPEED
+    } else if input.run {
+        PLAYER_RUN_SPEED
+    } else {
+        PLAYER_WALK_SPEED
+    };
+    let target_horizontal_velocity = wish_dir * target_speed;
+
+    let grounded = movement.grounded;
+
+    let horizontal_delta = target_horizontal_velocity - horizontal_velocity;
+
+    let accel = if grounded {
+        if local_input == Vec3::ZERO {
+            PLAYER_GROUND_DECELERATION
+        } else {
+            PLAYER_GROUND_ACCELERATION
+        }
+    } else if local_input == Vec3::ZERO {
+        0.0
+    } else {
+        PLAYER_AIR_ACCELERATION
+    };
+
+    let accel_factor = (accel * delta).min(1.0);
+    let air_control = if grounded { 1.0 } else { PLAYER_AIR_CONTROL };
+    let next_horizontal_velocity =
+        horizontal_velocity + horizontal_delta * accel_factor * air_control;
+
+    movement.velocity.x = next_horizontal_velocity.x;
+    movement.velocity.z = next_horizontal_velocity.z;
+
+    if grounded {
+        if movement.velocity.y < 0.0 {
+            movement.velocity.y = 0.0;
+        }
+
+        if input.jump && !movement.jump_queued {
+            movement.velocity.y = PLAYER_JUMP_SPEED;
+            movement.grounded = false;
+            movement.jump_queued = true;
+        }
+    } else {
+        movement.velocity.y -= PLAYER_GRAVITY * delta;
+    }
+
+    if !input.jump {
+        movement.jump_queued = false;
+    }
+
+    let filter = movement_filter(entity);
+    let character_pose = Pose::from_parts(transform.translation, Quat::IDENTITY);
+    let output = rapier_context.with_query_pipeline(filter, |query_pipeline| {
+        character_controller().move_shape(
+            delta,
+            &query_pipeline.query_pipeline,
+            &*collider.raw,
+            &character_pose,
+            movement.velocity * delta,
+            |_| {},
+        )
+    });
+
+    transform.translation += output.translation;
+    transform.rotation = Quat::from_rotation_y(input.camera.yaw);
+    movement.grounded = output.grounded;
+
+    if output.grounded && movement.velocity.y < 0.0 {
+        movement.velocity.y = 0.0;
+    }
+}
+
+pub fn set_crouched_state(
+    movement: &mut MovementState,
+    collider: &mut Collider,
+    transform: &mut Transform,
+    crouched: bool,
+) {
+    if movement.crouched == crouched {
+        return;
+    }
+
+    movement.crouched = crouched;
+    *collider = player_collider(crouched);
+    transform.translation.y += if crouched {
+        crouched_eye_height() - standing_eye_height()
+    } else {
+        standing_eye_height() - crouched_eye_height()
+    };
+}
+
+pub fn player_collider(crouched: bool) -> Collider {
+    Collider::capsule_y(
+        current_collider_half_height(crouched),
+        PLAYER_COLLIDER_RADIUS,
+    )
+}
+
+/// Groups to insert next to [`player_collider`].
+pub fn player_collision_groups() -> CollisionGroups {
+    CollisionGroups::new(PLAYER_COLLISION_GROUP, Group::ALL)
+}
+
+/// Static world colliders only, never the moving player itself or anyone else.
+fn movement_filter(entity: Entity) -> QueryFilter<'static> {
+    QueryFilter::new()
+        .exclude_collider(entity)
+        .exclude_sensors()
+        .groups(CollisionGroups::new(Group::ALL, !PLAYER_COLLISION_GROUP))
+}
+
+fn character_controller() -> CharacterController {
+    CharacterController {
+        autostep: Some(CharacterAutostep {
+            max_height: CharacterLength::Absolute(PLAYER_STEP_HEIGHT),
+            min_width: CharacterLength::Absolute(PLAYER_COLLIDER_RADIUS * 2.0),
+            include_dynamic_bodies: false,
+        }),
+        snap_to_ground: Some(CharacterLength::Absolute(0.2)),
+        ..Default::default()
+    }
+}
+
+fn can_stand_up(entity: Entity, rapier_context: &RapierContext<'_>, translation: Vec3) -> bool {
+    let standing_shape = player_collider(false);
+    let shape_position = translation + Vec3::Y * (standing_eye_height() - crouched_eye_height());
+    let filter = movement_filter(entity);
+
+    rapier_context
+        .cast_shape(
+            shape_position,
+            Quat::IDENTITY,
+            Vec3::ZERO,
+            (&standing_shape).into(),
+            ShapeCastOptions {
+                max_time_of_impact: 0.0,
+                stop_at_penetration: true,
+                compute_impact_geometry_on_penetration: false,
+                target_distance: 0.0,
+            },
+            filter,
+        )
+        .is_none()
+}
+
+fn current_collider_half_height(crouched: bool) -> f32 {
+    if crouched {
+        PLAYER_CROUCH_COLLIDER_HALF_HEIGHT
+    } else {
+        PLAYER_COLLIDER_HALF_HEIGHT
+    }
+}
+
+fn standing_eye_height() -> f32 {
+    PLAYER_COLLIDER_HALF_HEIGHT + PLAYER_COLLIDER_RADIUS
+}
+
+fn crouched_eye_height() -> f32 {
+    PLAYER_CROUCH_COLLIDER_HALF_HEIGHT + PLAYER_COLLIDER_RADIUS
+}
@@ -0,0 +1,369 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bevy::log::warn;
+use bevy_renet2::netcode::{ClientSocket, NetcodeTransportError, ServerSocket};
+use serde::Deserialize;
+
+/// Extra delay a reordered packet is held back by, so packets sent after it overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// What happens to packets going one way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConditions {
+    /// Milliseconds every packet is delayed by.
+    pub latency_ms: f32,
+    /// Milliseconds the latency varies by, either way. Varying delays also reorder packets.
+    pub jitter_ms: f32,
+    /// Chance of a packet being dropped, from 0 to 1.
+    pub loss: f32,
+    /// Chance of a packet arriving twice.
+    pub duplicate: f32,
+    /// Chance of a packet being held back behind later ones.
+    pub reorder: f32,
+}
+
+impl LinkConditions {
+    pub fn is_ideal(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let delays = [
+            ("latency_ms", self.latency_ms),
+            ("jitter_ms", self.jitter_ms),
+        ];
+        let chances = [
+            ("loss", self.loss),
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+        ];
+
+        for (name, value) in delays {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(format!("{} must be zero or positive, got {}", name, value));
+            }
+        }
+
+        for (name, value) in chances {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 1, got {}", name, value));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = (rand::random::<f32>() * 2.0 - 1.0) * self.jitter_ms;
+        let delay = Duration::from_secs_f32((self.latency_ms + jitter).max(0.0) / 1000.0);
+
+        if chance(self.reorder) {
+            delay + REORDER_DELAY
+        } else {
+            delay
+        }
+    }
+}
+
+impl FromStr for LinkConditions {
+    type Err = String;
+
+    /// Parses `latency=80,jitter=20,loss=0.02,duplicate=0.01,reorder=0.05`, any of them optional.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Self::default();
+
+        for pair in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {:?}", pair))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid value for {}: {}", key, e))?;
+
+            match key.trim() {
+                "latency" => conditions.latency_ms = value,
+                "jitter" => conditions.jitter_ms = value,
+                "loss" => conditions.loss = value,
+                "duplicate" => conditions.duplicate = value,
+                "reorder" => conditions.reorder = value,
+                key => {
+                    return Err(format!(
+                        "unknown condition {:?}, expected latency, jitter, loss, duplicate or \
+                         reorder",
+                        key
+                    ))
+                }
+            }
+        }
+
+        conditions.validate()?;
+
+        Ok(conditions)
+    }
+}
+
+impl fmt::Display for LinkConditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ms ± {} ms, {}% loss, {}% duplicated, {}% reordered",
+            self.latency_ms,
+            self.jitter_ms,
+            self.loss * 100.0,
+            self.duplicate * 100.0,
+            self.reorder * 100.0
+        )
+    }
+}
+
+/// Conditions per direction, as seen from the local socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConditions {
+    /// Packets received by this side.
+    pub inbound: LinkConditions,
+    /// Packets sent by this side.
+    pub outbound: LinkConditions,
+}
+
+impl NetworkConditions {
+    pub fn is_ideal(&self) -> bool {
+        self.inbound.is_ideal() && self.outbound.is_ideal()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.inbound
+            .validate()
+            .map_err(|e| format!("inbound {}", e))?;
+        self.outbound
+            .validate()
+            .map_err(|e| format!("outbound {}", e))
+    }
+}
+
+fn chance(probability: f32) -> bool {
+    probability > 0.0 && rand::random::<f32>() < probability
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DelayedPacket {
+    release_at: Instant,
+    /// Keeps packets released at the same instant in the order they were queued.
+    sequence: u64,
+    addr: SocketAddr,
+    payload: Vec<u8>,
+}
+
+/// Packets on their way through a simulated link, earliest release first.
+#[derive(Debug, Default)]
+struct DelayQueue {
+    packets: BinaryHeap<Reverse<DelayedPacket>>,
+    next_sequence: u64,
+}
+
+impl DelayQueue {
+    fn push(&mut self, link: &LinkConditions, now: Instant, addr: SocketAddr, payload: &[u8]) {
+        if chance(link.loss) {
+            return;
+        }
+
+        let copies = if chance(link.duplicate) { 2 } else { 1 };
+
+        for _ in 0..copies {
+            self.packets.push(Reverse(DelayedPacket {
+                release_at: now + link.delay(),
+                sequence: self.next_sequence,
+                addr,
+                payload: payload.to_vec(),
+            }));
+            self.next_sequence += 1;
+        }
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<DelayedPacket> {
+        if self.packets.peek()?.0.release_at > now {
+            return None;
+        }
+
+        self.packets.pop().map(|Reverse(packet)| packet)
+    }
+}
+
+/// Wraps a renet2 socket, delaying, dropping, duplicating and reordering packets both ways.
+///
+/// Outgoing packets are only sent once due, on a later `send` or on `postupdate`, so the delay is
+/// rounded up to the frame rate of the side sending them.
+#[derive(Debug)]
+pub struct SimulatedSocket<S> {
+    inner: S,
+    conditions: NetworkConditions,
+    inbound: DelayQueue,
+    outbound: DelayQueue,
+}
+
+impl<S> SimulatedSocket<S> {
+    pub fn new(inner: S, conditions: NetworkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            inbound: DelayQueue::default(),
+            outbound: DelayQueue::default(),
+        }
+    }
+
+    /// Queues everything the inner socket received, then hands out the first packet that is due.
+    fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        mut recv: impl FnMut(&mut S, &mut [u8]) -> io::Result<(usize, SocketAddr)>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let now = Instant::now();
+
+        loop {
+            match recv(&mut self.inner, buffer) {
+                Ok((len, addr)) => {
+                    self.inbound
+                        .push(&self.conditions.inbound, now, addr, &buffer[..len]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let packet = self.inbound.pop_due(now).ok_or(io::ErrorKind::WouldBlock)?;
+
+        // Came in through a buffer of the same size.
+        buffer[..packet.payload.len()].copy_from_slice(&packet.payload);
+
+        Ok((packet.payload.len(), packet.addr))
+    }
+
+    fn flush(
+        &mut self,
+        mut send: impl FnMut(&mut S, SocketAddr, &[u8]) -> Result<(), NetcodeTransportError>,
+    ) -> Result<(), NetcodeTransportError> {
+        let now = Instant::now();
+
+        while let Some(packet) = self.outbound.pop_due(now) {
+            send(&mut self.inner, packet.addr, &packet.payload)?;
+        }
+
+        Ok(())
+    }
+
+    fn queue(&mut self, addr: SocketAddr, packet: &[u8]) {
+        self.outbound
+            .push(&self.conditions.outbound, Instant::now(), addr, packet);
+    }
+}
+
+impl<S: ServerSocket> ServerSocket for SimulatedSocket<S> {
+    fn is_encrypted(&self) -> bool {
+        self.inner.is_encrypted()
+    }
+
+    fn is_reliable(&self) -> bool {
+        self.inner.is_reliable()
+    }
+
+    fn addr(&self) -> io::Result<SocketAddr> {
+        self.inner.addr()
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn connection_denied(&mut self, addr: SocketAddr) {
+        self.inner.connection_denied(addr);
+    }
+
+    fn connection_accepted(&mut self, client_id: u64, addr: SocketAddr) {
+        self.inner.connection_accepted(client_id, addr);
+    }
+
+    fn disconnect(&mut self, addr: SocketAddr) {
+        self.inner.disconnect(addr);
+    }
+
+    fn preupdate(&mut self) {
+        self.inner.preupdate();
+    }
+
+    fn try_recv(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.receive(buffer, |inner, buffer| inner.try_recv(buffer))
+    }
+
+    fn postupdate(&mut self) {
+        if let Err(e) = self.flush(|inner, addr, packet| inner.send(addr, packet)) {
+            warn!("Dropping delayed packet: {}", e);
+        }
+
+        self.inner.postupdate();
+    }
+
+    fn send(&mut self, addr: SocketAddr, packet: &[u8]) -> Result<(), NetcodeTransportError> {
+        self.queue(addr, packet);
+        self.flush(|inner, addr, packet| inner.send(addr, packet))
+    }
+}
+
+impl<S: ClientSocket> ClientSocket for SimulatedSocket<S> {
+    fn is_encrypted(&self) -> bool {
+        self.inner.is_encrypted()
+    }
+
+    fn is_reliable(&self) -> bool {
+        self.inner.is_reliable()
+    }
+
+    fn addr(&self) -> io::Result<SocketAddr> {
+        self.inner.addr()
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn preupdate(&mut self) {
+        self.inner.preupdate();
+    }
+
+    fn try_recv(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.receive(buffer, |inner, buffer| inner.try_recv(buffer))
+    }
+
+    fn postupdate(&mut self) {
+        if let Err(e) = self.flush(|inner, addr, packet| inner.send(addr, packet)) {
+            warn!("Dropping delayed packet: {}", e);
+        }
+
+        self.inner.postupdate();
+    }
+
+    fn send(&mut self, addr: SocketAddr, packet: &[u8]) -> Result<(), NetcodeTransportError> {
+        self.queue(addr, packet);
+        self.flush(|inner, addr, packet| inner.send(addr, packet))
+    }
+}
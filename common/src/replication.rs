@@ -0,0 +1,393 @@
+use std::any::type_name;
+
+use bevy::{
+    log::warn,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use bevy_renet2::prelude::{ClientId, RenetClient, RenetServer};
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::{
+    data::{self, Channel, Compression, Decode, DecodeError, Encode},
+    Client, Envelope,
+};
+
+/// A component the server sends to clients, registered with [`AppReplicationExt::replicate`].
+///
+/// Changes go out reliably on the tick they are made, which suits state that changes now and
+/// then, like who is connected, how players look and their health. Per-tick player state, movement
+/// and weapon timers, stays in snapshots: those are quantized, delta encoded against what the
+/// client acknowledged and sent unreliably, none of which fits reliable messages.
+///
+/// Components are only ever added or changed on clients, despawn the entity to get rid of them.
+pub trait Replicated: Component + Clone + Encode + Decode {
+    const AUDIENCE: Audience = Audience::Everyone;
+}
+
+/// Who a [`Replicated`] component is sent to.
+///
+/// A client is sent an entity as long as it may be sent any of its components, and told to
+/// despawn it once it may not anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Audience {
+    Everyone,
+    /// Only the client named by the entity's [`NetworkOwner`], nobody if it has none.
+    Owner,
+    /// Only clients the server's [`ReplicationInterest`] lists the entity for.
+    Interest,
+}
+
+/// Marks a server entity for replication, it is given a [`NetworkId`] and spawned on clients.
+#[derive(Debug, Default, Component)]
+pub struct Replicate;
+
+/// Stable id of a replicated entity, the same on the server and every client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Archive, Serialize, Deserialize, Component)]
+pub struct NetworkId(pub u64);
+
+/// The client an entity belongs to, the only one sent its [`Audience::Owner`] components.
+#[derive(Debug, Clone, Copy, Component)]
+pub struct NetworkOwner(pub ClientId);
+
+/// Server entities each client is sent the [`Audience::Interest`] components of, kept up to date
+/// by the server before [`send_replication`]. Clients it does not list get none of them.
+#[derive(Debug, Default, Resource)]
+pub struct ReplicationInterest(pub HashMap<ClientId, HashSet<Entity>>);
+
+/// Local entity of every replicated one, on both sides.
+#[derive(Debug, Default, Resource)]
+pub struct NetworkEntities {
+    entities: HashMap<NetworkId, Entity>,
+    next_id: u64,
+}
+
+impl NetworkEntities {
+    pub fn get(&self, id: NetworkId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+}
+
+/// What one of the server's clients was last sent, missing until its first replication run.
+#[derive(Debug, Default, Component)]
+pub struct ReplicationScope {
+    entities: HashSet<NetworkId>,
+    /// Components by kind, resent in full when they come back into scope.
+    components: HashSet<(NetworkId, u16)>,
+}
+
+/// Everything that changed among replicated entities since the previous message.
+#[derive(Debug, Default, Archive, Serialize, Deserialize)]
+pub struct ReplicationMessage {
+    pub spawned: Vec<NetworkId>,
+    pub updated: Vec<ComponentUpdate>,
+    pub despawned: Vec<NetworkId>,
+}
+
+impl ReplicationMessage {
+    fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.updated.is_empty() && self.despawned.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+pub struct ComponentUpdate {
+    pub entity: NetworkId,
+    /// Position of the component in the [`ReplicationRegistry`].
+    pub kind: u16,
+    pub payload: Vec<u8>,
+}
+
+/// A replicated component as found on the server.
+struct Collected {
+    entity: Entity,
+    id: NetworkId,
+    changed: bool,
+}
+
+struct ComponentKind {
+    name: &'static str,
+    audience: Audience,
+    collect: fn(&mut World) -> Vec<Collected>,
+    serialize: fn(&World, Entity) -> Vec<u8>,
+    apply: fn(&mut World, Entity, &[u8]) -> Result<(), DecodeError>,
+}
+
+impl ComponentKind {
+    fn of<T: Replicated>() -> Self {
+        Self {
+            name: type_name::<T>(),
+            audience: T::AUDIENCE,
+            collect: collect::<T>,
+            serialize: serialize::<T>,
+            apply: apply::<T>,
+        }
+    }
+}
+
+/// Every [`Replicated`] component, both sides have to register the same ones in the same order.
+#[derive(Default, Resource)]
+pub struct ReplicationRegistry {
+    kinds: Vec<ComponentKind>,
+}
+
+pub trait AppReplicationExt {
+    fn replicate<T: Replicated>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for App {
+    fn replicate<T: Replicated>(&mut self) -> &mut Self {
+        self.init_resource::<ReplicationRegistry>()
+            .init_resource::<NetworkEntities>()
+            .init_resource::<ReplicationInterest>();
+
+        let mut registry = self.world_mut().resource_mut::<ReplicationRegistry>();
+        assert!(
+            registry.kinds.len() < u16::MAX as usize,
+            "Too many replicated components"
+        );
+        registry.kinds.push(ComponentKind::of::<T>());
+
+        self
+    }
+}
+
+fn collect<T: Replicated>(world: &mut World) -> Vec<Collected> {
+    let mut query = world.query::<(Entity, &NetworkId, Ref<T>)>();
+
+    query
+        .iter(world)
+        .map(|(entity, id, component)| Collected {
+            entity,
+            id: *id,
+            changed: component.is_changed(),
+        })
+        .collect()
+}
+
+fn serialize<T: Replicated>(world: &World, entity: Entity) -> Vec<u8> {
+    let component = world
+        .get::<T>(entity)
+        .expect("Collected component to still exist");
+
+    data::to_payload(component)
+}
+
+fn apply<T: Replicated>(
+    world: &mut World,
+    entity: Entity,
+    payload: &[u8],
+) -> Result<(), DecodeError> {
+    let component: T = data::from_payload(payload)?;
+
+    // Gone already when the client is tearing the game down.
+    if let Ok(mut entity) = world.get_entity_mut(entity) {
+        entity.insert(component);
+    }
+
+    Ok(())
+}
+
+/// Sends every client the replicated entities that came into its scope, the components that
+/// changed on those it has and despawns for those that left it.
+pub fn send_replication(world: &mut World) {
+    world.resource_scope(|world, mut entities: Mut<NetworkEntities>| {
+        let mut unassigned =
+            world.query_filtered::<Entity, (With<Replicate>, Without<NetworkId>)>();
+        for entity in unassigned.iter(world).collect::<Vec<_>>() {
+            let id = NetworkId(entities.next_id);
+            entities.next_id += 1;
+            entities.entities.insert(id, entity);
+            world.entity_mut(entity).insert(id);
+        }
+
+        // Despawned entities leave every scope below.
+        entities
+            .entities
+            .retain(|&id, &mut entity| world.get::<NetworkId>(entity) == Some(&id));
+
+        let registry = world.resource::<ReplicationRegistry>();
+        let kinds: Vec<_> = registry
+            .kinds
+            .iter()
+            .map(|kind| (kind.audience, kind.collect, kind.serialize))
+            .collect();
+        let collected: Vec<Vec<Collected>> = kinds
+            .iter()
+            .map(|&(_, collect, _)| collect(world))
+            .collect();
+
+        // Sent to everyone, having no component to restrict them.
+        let with_components: HashSet<NetworkId> = collected
+            .iter()
+            .flatten()
+            .map(|component| component.id)
+            .collect();
+        let bare: Vec<NetworkId> = entities
+            .entities
+            .keys()
+            .filter(|id| !with_components.contains(*id))
+            .copied()
+            .collect();
+
+        let mut owners = world.query::<(&NetworkId, &NetworkOwner)>();
+        let owners: HashMap<NetworkId, ClientId> = owners
+            .iter(world)
+            .map(|(id, owner)| (*id, owner.0))
+            .collect();
+
+        let mut clients = world.query::<(
+            Entity,
+            &Client,
+            Option<&Compression>,
+            Option<&mut ReplicationScope>,
+        )>();
+        let clients: Vec<(Entity, ClientId, Compression, ReplicationScope)> = clients
+            .iter_mut(world)
+            .map(|(entity, client, compression, scope)| {
+                (
+                    entity,
+                    client.id,
+                    compression.copied().unwrap_or_default(),
+                    scope
+                        .map(|mut scope| std::mem::take(&mut *scope))
+                        .unwrap_or_default(),
+                )
+            })
+            .collect();
+
+        let interest = world.resource::<ReplicationInterest>();
+        let no_interest = HashSet::new();
+        let mut payloads: HashMap<(usize, Entity), Vec<u8>> = HashMap::new();
+        let mut outgoing = Vec::new();
+        let mut scopes = Vec::new();
+
+        for (entity, client_id, compression, scope) in clients {
+            let interesting = interest.0.get(&client_id).unwrap_or(&no_interest);
+            let audible = |audience: Audience, component: &Collected| match audience {
+                Audience::Everyone => true,
+                Audience::Owner => owners.get(&component.id) == Some(&client_id),
+                Audience::Interest => interesting.contains(&component.entity),
+            };
+
+            let mut in_scope: HashSet<NetworkId> = bare.iter().copied().collect();
+            for (&(audience, _, _), components) in kinds.iter().zip(&collected) {
+                in_scope.extend(
+                    components
+                        .iter()
+                        .filter(|component| audible(audience, component))
+                        .map(|component| component.id),
+                );
+            }
+
+            let mut message = ReplicationMessage {
+                spawned: in_scope.difference(&scope.entities).copied().collect(),
+                despawned: scope.entities.difference(&in_scope).copied().collect(),
+                ..default()
+            };
+            let mut components = HashSet::new();
+
+            for (kind, (&(audience, _, serialize), collected)) in
+                kinds.iter().zip(&collected).enumerate()
+            {
+                for component in collected {
+                    if !in_scope.contains(&component.id) || !audible(audience, component) {
+                        continue;
+                    }
+
+                    let key = (component.id, kind as u16);
+                    if component.changed || !scope.components.contains(&key) {
+                        let payload = payloads
+                            .entry((kind, component.entity))
+                            .or_insert_with(|| serialize(world, component.entity));
+
+                        message.updated.push(ComponentUpdate {
+                            entity: component.id,
+                            kind: kind as u16,
+                            payload: payload.clone(),
+                        });
+                    }
+                    components.insert(key);
+                }
+            }
+
+            if !message.is_empty() {
+                outgoing.push((client_id, compression, message));
+            }
+            scopes.push((
+                entity,
+                ReplicationScope {
+                    entities: in_scope,
+                    components,
+                },
+            ));
+        }
+
+        let mut server = world.resource_mut::<RenetServer>();
+        for (client_id, compression, message) in outgoing {
+            let message = data::encode_with(&Envelope::Replication(message), compression);
+            server.send_message(client_id, Channel::Replication, message);
+        }
+
+        for (entity, scope) in scopes {
+            world.entity_mut(entity).insert(scope);
+        }
+    });
+}
+
+/// Spawns, updates and despawns replicated entities as the server says.
+pub fn receive_replication(world: &mut World) {
+    let Some(mut client) = world.get_resource_mut::<RenetClient>() else {
+        return;
+    };
+
+    let messages: Vec<_> =
+        std::iter::from_fn(|| client.receive_message(Channel::Replication)).collect();
+
+    world.resource_scope(|world, registry: Mut<ReplicationRegistry>| {
+        world.resource_scope(|world, mut entities: Mut<NetworkEntities>| {
+            for message in messages {
+                let message = match data::decode(&message) {
+                    Ok(Envelope::Replication(message)) => message,
+                    Ok(_) => {
+                        warn!("Dropping unexpected message on the replication channel");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Dropping replication message: {}", e);
+                        continue;
+                    }
+                };
+
+                for id in message.spawned {
+                    if !entities.entities.contains_key(&id) {
+                        let entity = world.spawn(id).id();
+                        entities.entities.insert(id, entity);
+                    }
+                }
+
+                for update in message.updated {
+                    let Some(&entity) = entities.entities.get(&update.entity) else {
+                        warn!("Dropping update of unknown entity {:?}", update.entity);
+                        continue;
+                    };
+                    let Some(kind) = registry.kinds.get(update.kind as usize) else {
+                        warn!("Dropping update of unknown component {}", update.kind);
+                        continue;
+                    };
+
+                    if let Err(e) = (kind.apply)(world, entity, &update.payload) {
+                        warn!("Dropping {} update: {}", kind.name, e);
+                    }
+                }
+
+                for id in message.despawned {
+                    if let Some(entity) = entities.entities.remove(&id) {
+                        // Already gone when the client is tearing the game down.
+                        let _ = world.try_despawn(entity);
+                    }
+                }
+            }
+        });
+    });
+}
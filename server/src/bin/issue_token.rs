@@ -0,0 +1,49 @@
+//! Mints a connect token for a player, signed with the server's `NETCODE_PRIVATE_KEY`.
+//!
+//! Usage: `issue_token <player-name> <server-addr> [output-file]`
+
+use std::{fs::File, net::SocketAddr, process::ExitCode};
+
+use common::auth;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(path) => {
+            println!("Connect token written to {}", path);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<String, String> {
+    let mut args = std::env::args().skip(1);
+
+    let (Some(player_name), Some(server_addr)) = (args.next(), args.next()) else {
+        return Err("Usage: issue_token <player-name> <server-addr> [output-file]".to_owned());
+    };
+    let path = args
+        .next()
+        .unwrap_or_else(|| format!("{}.token", player_name));
+
+    let server_addr: SocketAddr = server_addr
+        .parse()
+        .map_err(|e| format!("Invalid server address {}: {}", server_addr, e))?;
+
+    let private_key = std::env::var("NETCODE_PRIVATE_KEY")
+        .map_err(|_| "NETCODE_PRIVATE_KEY is not set".to_owned())?;
+    let private_key = auth::parse_private_key(&private_key).map_err(|e| e.to_string())?;
+
+    let token = auth::issue_connect_token(&private_key, vec![server_addr], &player_name)
+        .map_err(|e| e.to_string())?;
+
+    let mut file = File::create(&path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+    token
+        .write(&mut file)
+        .map_err(|e| format!("Could not write {}: {}", path, e))?;
+
+    Ok(path)
+}
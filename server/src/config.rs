@@ -0,0 +1,336 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::Resource;
+use bevy_renet2::netcode::NETCODE_KEY_BYTES;
+use clap::Parser;
+use common::{
+    DEFAULT_PORT, TICK_RATE, auth, map,
+    net_sim::{LinkConditions, NetworkConditions},
+};
+use serde::Deserialize;
+
+/// Read when no `--config` is given and the file exists.
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+/// Netcode refuses to start with more slots than this.
+const MAX_CLIENTS_LIMIT: usize = 1024;
+/// Longest rewind window allowed, the pose history grows with it and clients may claim that old a
+/// view of the world.
+const MAX_REWIND_MS_LIMIT: u64 = 1000;
+
+#[derive(Debug, Parser)]
+#[command(about = "Headless game server")]
+struct Cli {
+    /// TOML config file, options given here take precedence over it.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to bind, `::` listens on IPv6 too.
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Address clients dial, written into connect tokens. Defaults to the bind address.
+    #[arg(long)]
+    public_addr: Option<SocketAddr>,
+    #[arg(long)]
+    max_clients: Option<usize>,
+    /// Simulation ticks per second.
+    #[arg(long)]
+    tick_rate: Option<f64>,
+    /// World snapshots sent per second, the tick rate divided by a whole number.
+    #[arg(long)]
+    snapshot_rate: Option<f64>,
+    /// Lower the snapshot rate of clients losing packets or over their bandwidth limit.
+    #[arg(long)]
+    adaptive_snapshot_rate: bool,
+    /// Fewest snapshots per second an adaptive rate goes down to.
+    #[arg(long)]
+    min_snapshot_rate: Option<f64>,
+    /// Kilobytes per second sent to one client before its snapshot rate is lowered.
+    #[arg(long)]
+    client_bandwidth_limit: Option<f64>,
+    #[arg(long)]
+    map: Option<String>,
+    /// Distance beyond which entities are left out of a player's snapshots.
+    #[arg(long)]
+    interest_radius: Option<f32>,
+    /// Milliseconds back in time shots may be resolved against player colliders, at most 1000.
+    #[arg(long)]
+    max_rewind_ms: Option<u64>,
+    /// Simulated conditions for packets from clients, e.g. `latency=80,jitter=20,loss=0.02`.
+    #[arg(long)]
+    sim_inbound: Option<LinkConditions>,
+    /// Simulated conditions for packets to clients, same format as `--sim-inbound`.
+    #[arg(long)]
+    sim_outbound: Option<LinkConditions>,
+    /// Send everything uncompressed, even to clients accepting compression.
+    #[arg(long)]
+    no_compression: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Resource)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub public_addr: Option<SocketAddr>,
+    pub max_clients: usize,
+    pub tick_rate: f64,
+    pub snapshot_rate: f64,
+    /// Sends fewer snapshots to clients losing packets or over `client_bandwidth_limit`.
+    pub adaptive_snapshot_rate: bool,
+    /// Fewest snapshots per second an adaptive rate goes down to.
+    pub min_snapshot_rate: f64,
+    /// Kilobytes per second sent to one client before its snapshot rate is lowered.
+    pub client_bandwidth_limit: f64,
+    pub map: String,
+    /// Distance beyond which entities are left out of a player's snapshots.
+    pub interest_radius: f32,
+    /// Milliseconds back in time shots may be resolved against player colliders.
+    pub max_rewind_ms: u64,
+    /// Compress large messages to clients that accept it.
+    pub compression: bool,
+    /// Latency, jitter, loss, duplication and reordering injected for testing, off by default.
+    pub network_sim: NetworkConditions,
+    /// Hex encoded netcode key, `NETCODE_PRIVATE_KEY` takes precedence.
+    /// Without one any client id is accepted.
+    pub private_key: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            port: DEFAULT_PORT,
+            public_addr: None,
+            max_clients: 32,
+            tick_rate: TICK_RATE,
+            snapshot_rate: 64.0,
+            adaptive_snapshot_rate: false,
+            min_snapshot_rate: 20.0,
+            client_bandwidth_limit: 64.0,
+            map: map::DEFAULT_MAP.to_owned(),
+            interest_radius: 60.0,
+            max_rewind_ms: 200,
+            compression: true,
+            network_sim: NetworkConditions::default(),
+            private_key: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file, applies command-line overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let cli = Cli::parse();
+
+        let mut config = match &cli.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        if let Some(bind) = cli.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(public_addr) = cli.public_addr {
+            config.public_addr = Some(public_addr);
+        }
+        if let Some(max_clients) = cli.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(tick_rate) = cli.tick_rate {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(snapshot_rate) = cli.snapshot_rate {
+            config.snapshot_rate = snapshot_rate;
+        }
+        if cli.adaptive_snapshot_rate {
+            config.adaptive_snapshot_rate = true;
+        }
+        if let Some(min_snapshot_rate) = cli.min_snapshot_rate {
+            config.min_snapshot_rate = min_snapshot_rate;
+        }
+        if let Some(client_bandwidth_limit) = cli.client_bandwidth_limit {
+            config.client_bandwidth_limit = client_bandwidth_limit;
+        }
+        if let Some(map) = cli.map {
+            config.map = map;
+        }
+        if let Some(interest_radius) = cli.interest_radius {
+            config.interest_radius = interest_radius;
+        }
+        if let Some(max_rewind_ms) = cli.max_rewind_ms {
+            config.max_rewind_ms = max_rewind_ms;
+        }
+        if cli.no_compression {
+            config.compression = false;
+        }
+        if let Some(inbound) = cli.sim_inbound {
+            config.network_sim.inbound = inbound;
+        }
+        if let Some(outbound) = cli.sim_outbound {
+            config.network_sim.outbound = outbound;
+        }
+        if let Ok(private_key) = std::env::var("NETCODE_PRIVATE_KEY") {
+            config.private_key = Some(private_key);
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_owned(),
+            error: e,
+        })?;
+
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse {
+            path: path.to_owned(),
+            error: e,
+        })
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(1..=MAX_CLIENTS_LIMIT).contains(&self.max_clients) {
+            return Err(ConfigError::Invalid(format!(
+                "max_clients must be between 1 and {}, got {}",
+                MAX_CLIENTS_LIMIT, self.max_clients
+            )));
+        }
+
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "tick_rate must be positive, got {}",
+                self.tick_rate
+            )));
+        }
+
+        if !(self.snapshot_rate > 0.0 && self.snapshot_rate <= self.tick_rate) {
+            return Err(ConfigError::Invalid(format!(
+                "snapshot_rate must be positive and at most tick_rate ({}), got {}",
+                self.tick_rate, self.snapshot_rate
+            )));
+        }
+
+        // Snapshots go out every so many ticks, any other rate would silently be rounded.
+        let interval = self.tick_rate / self.snapshot_rate;
+        if (interval - interval.round()).abs() > 1e-6 {
+            return Err(ConfigError::Invalid(format!(
+                "snapshot_rate must divide tick_rate ({}) into a whole number of ticks, got {}",
+                self.tick_rate, self.snapshot_rate
+            )));
+        }
+
+        if !(self.min_snapshot_rate > 0.0 && self.min_snapshot_rate <= self.snapshot_rate) {
+            return Err(ConfigError::Invalid(format!(
+                "min_snapshot_rate must be positive and at most snapshot_rate ({}), got {}",
+                self.snapshot_rate, self.min_snapshot_rate
+            )));
+        }
+
+        if !(self.client_bandwidth_limit.is_finite() && self.client_bandwidth_limit > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "client_bandwidth_limit must be positive, got {}",
+                self.client_bandwidth_limit
+            )));
+        }
+
+        if !map::MAPS.contains(&self.map.as_str()) {
+            return Err(ConfigError::Invalid(format!(
+                "unknown map {:?}, available maps: {}",
+                self.map,
+                map::MAPS.join(", ")
+            )));
+        }
+
+        if !(self.interest_radius.is_finite() && self.interest_radius > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "interest_radius must be positive, got {}",
+                self.interest_radius
+            )));
+        }
+
+        if self.max_rewind_ms > MAX_REWIND_MS_LIMIT {
+            return Err(ConfigError::Invalid(format!(
+                "max_rewind_ms must be at most {}, got {}",
+                MAX_REWIND_MS_LIMIT, self.max_rewind_ms
+            )));
+        }
+
+        self.network_sim
+            .validate()
+            .map_err(|e| ConfigError::Invalid(format!("network_sim {}", e)))?;
+
+        if let Some(private_key) = &self.private_key {
+            auth::parse_private_key(private_key)
+                .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Connect tokens name the address clients dial, which may differ from the bind address.
+    pub fn public_addr(&self) -> SocketAddr {
+        self.public_addr.unwrap_or_else(|| self.bind_addr())
+    }
+
+    /// Also checked in `validate`, so a bad key fails startup before anything is bound.
+    pub fn private_key(&self) -> Result<Option<[u8; NETCODE_KEY_BYTES]>, auth::AuthError> {
+        self.private_key
+            .as_deref()
+            .map(auth::parse_private_key)
+            .transpose()
+    }
+
+    /// Simulation ticks between two world snapshots, a whole number checked in `validate`.
+    pub fn snapshot_interval(&self) -> u32 {
+        (self.tick_rate / self.snapshot_rate).round() as u32
+    }
+
+    /// Most snapshot intervals an adaptive rate stretches the gap between two snapshots to.
+    pub fn max_snapshot_multiplier(&self) -> u32 {
+        (self.snapshot_rate / self.min_snapshot_rate)
+            .floor()
+            .max(1.0) as u32
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { path, error } => write!(f, "could not read {}: {}", path.display(), error),
+            Self::Parse { path, error } => write!(f, "invalid {}: {}", path.display(), error),
+            Self::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -1,7 +1,4 @@
-use std::{
-    net::{Ipv4Addr, UdpSocket},
-    time::SystemTime,
-};
+use std::{net::UdpSocket, time::SystemTime};
 
 use bevy::{log::LogPlugin, prelude::*, transform::TransformPlugin};
 
@@ -13,58 +10,107 @@ use bevy_renet2::{
     prelude::{RenetServer, RenetServerPlugin},
 };
 
-use common::*;
+use common::{net_sim::SimulatedSocket, *};
+
+use crate::config::ServerConfig;
 
+mod config;
 mod tick;
+mod violations;
+
+fn main() -> AppExit {
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid server configuration: {}", e);
+            return AppExit::error();
+        }
+    };
+
+    let (server, transport) = match renet_init(&config) {
+        Ok(net) => net,
+        Err(e) => {
+            eprintln!("Could not start server on {}: {}", config.bind_addr(), e);
+            return AppExit::error();
+        }
+    };
 
-fn main() {
     App::new()
         .add_plugins(LogPlugin::default())
         .add_plugins(MinimalPlugins)
         .add_plugins(TransformPlugin)
-        .add_plugins(ServerPlugin)
-        .run();
+        .insert_resource(server)
+        .insert_resource(transport)
+        .add_plugins(ServerPlugin { config })
+        .run()
 }
 
-struct ServerPlugin;
+struct ServerPlugin {
+    config: ServerConfig,
+}
 
 impl bevy::prelude::Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
-        let (server, transport) = renet_init();
-
-        app.add_plugins(common::Plugin)
+        info!(
+            "Starting server on {} (public {}), {} Hz, map {}",
+            self.config.bind_addr(),
+            self.config.public_addr(),
+            self.config.tick_rate,
+            self.config.map
+        );
+
+        if self.config.private_key.is_none() {
+            warn!("No private key configured, accepting unauthenticated clients");
+        }
+
+        if !self.config.network_sim.is_ideal() {
+            warn!(
+                "Simulating network conditions, inbound {}, outbound {}",
+                self.config.network_sim.inbound, self.config.network_sim.outbound
+            );
+        }
+
+        app.insert_resource(self.config.clone())
+            .insert_resource(TickRate(self.config.tick_rate))
+            .add_plugins(common::Plugin)
             .add_plugins(RenetServerPlugin)
             .add_plugins(NetcodeServerPlugin)
             .add_plugins(tick::Plugin)
-            .insert_resource(server)
-            .insert_resource(transport);
+            .add_plugins(violations::Plugin);
     }
 }
 
-fn renet_init() -> (RenetServer, NetcodeServerTransport) {
-    let public_addr = (Ipv4Addr::UNSPECIFIED, DEFAULT_PORT).into();
-
-    info!("Starting server on: {:?}", public_addr);
+fn renet_init(
+    config: &ServerConfig,
+) -> Result<(RenetServer, NetcodeServerTransport), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(config.bind_addr())?;
 
-    let socket = UdpSocket::bind(public_addr).unwrap();
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
 
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
+    let authentication = match config.private_key()? {
+        Some(private_key) => ServerAuthentication::Secure { private_key },
+        None => ServerAuthentication::Unsecure,
+    };
 
     let server_config = ServerSetupConfig {
         current_time,
-        max_clients: 32,
+        max_clients: config.max_clients,
         protocol_id: PROTOCOL_ID,
-        socket_addresses: vec![vec![public_addr]],
-        authentication: ServerAuthentication::Unsecure,
+        socket_addresses: vec![vec![config.public_addr()]],
+        authentication,
     };
 
-    let socket = NativeSocket::new(socket).unwrap();
+    let socket = NativeSocket::new(socket)?;
 
-    let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
+    let transport = if config.network_sim.is_ideal() {
+        NetcodeServerTransport::new(server_config, socket)?
+    } else {
+        let socket = SimulatedSocket::new(socket, config.network_sim);
+
+        NetcodeServerTransport::new(server_config, socket)?
+    };
 
     let server = RenetServer::new(common::data::renet_config());
 
-    (server, transport)
+    Ok((server, transport))
 }
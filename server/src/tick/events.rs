@@ -0,0 +1,107 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_rapier3d::prelude::*;
+use bevy_renet2::prelude::{ClientId, RenetServer};
+use common::{
+    Client, Envelope, GameEvent,
+    data::{self, Channel, Compression},
+};
+
+use super::{
+    WorldState,
+    line_of_sight::{LineOfSight, coarse_shot_position},
+};
+use crate::config::ServerConfig;
+
+/// Events raised during the current tick, sent once the tick is simulated.
+#[derive(Debug, Default, Resource)]
+pub struct PendingEvents(Vec<GameEvent>);
+
+impl PendingEvents {
+    pub fn push(&mut self, event: GameEvent) {
+        self.0.push(event);
+    }
+}
+
+/// Sends every player the events of this tick that concern it, as one reliable message.
+///
+/// Shots from players the recipient could not see or hear say only roughly where they came
+/// from, the line of sight checks are the ones this tick's snapshots were culled with.
+pub fn send_game_events(
+    mut server: ResMut<RenetServer>,
+    mut pending: ResMut<PendingEvents>,
+    mut line_of_sight: ResMut<LineOfSight>,
+    world_state: Res<WorldState>,
+    config: Res<ServerConfig>,
+    rapier_context: ReadRapierContext,
+    players: Query<(&Client, &Transform, &Compression)>,
+) {
+    if pending.0.is_empty() {
+        return;
+    }
+
+    let rapier_context = rapier_context
+        .single()
+        .expect("Default Rapier context to exist");
+    let is_not_player = |entity| !players.contains(entity);
+    let filter = QueryFilter::new()
+        .exclude_sensors()
+        .predicate(&is_not_player);
+
+    let mut outgoing: HashMap<ClientId, (Compression, Vec<GameEvent>)> = HashMap::new();
+
+    for event in pending.0.drain(..) {
+        for (client, transform, compression) in players.iter() {
+            let event = match &event {
+                // Heard within the same radius snapshots are limited to.
+                GameEvent::ShotFired {
+                    projectile_id,
+                    shooter,
+                    weapon,
+                    pos,
+                } => {
+                    let heard =
+                        transform.translation.distance(Vec3::from(*pos)) <= config.interest_radius;
+                    let seen = *shooter == client.id
+                        || line_of_sight.visible(&rapier_context, filter, client.id, *shooter);
+
+                    heard.then(|| GameEvent::ShotFired {
+                        projectile_id: *projectile_id,
+                        shooter: *shooter,
+                        weapon: *weapon,
+                        pos: if seen {
+                            *pos
+                        } else {
+                            coarse_shot_position(Vec3::from(*pos)).into()
+                        },
+                    })
+                }
+                GameEvent::PlayerDamaged { id, attacker, .. } => {
+                    (client.id == *id || client.id == *attacker).then(|| event.clone())
+                }
+                GameEvent::PlayerKilled { .. } | GameEvent::PlayerRespawned { .. } => {
+                    Some(event.clone())
+                }
+            };
+
+            if let Some(event) = event {
+                outgoing
+                    .entry(client.id)
+                    .or_insert_with(|| (*compression, Vec::new()))
+                    .1
+                    .push(event);
+            }
+        }
+    }
+
+    for (client_id, (compression, events)) in outgoing {
+        let message = data::encode_with(
+            &Envelope::Events {
+                tick: world_state.tick,
+                events,
+            },
+            compression,
+        );
+
+        server.send_message(client_id, Channel::GameEvent, message);
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use common::ClientInput;
+
+/// Inputs buffered before playback (re)starts, absorbing jitter in their arrival.
+const JITTER_BUFFER_TICKS: usize = 2;
+/// Past this many buffered inputs the oldest are dropped, so a burst can't add lasting delay.
+const MAX_BUFFERED_INPUTS: usize = 16;
+
+/// Inputs received from a player but not simulated yet, ordered by sequence.
+#[derive(Debug, Default, Component)]
+pub struct InputBuffer {
+    inputs: VecDeque<ClientInput>,
+    last_consumed: u32,
+    playing: bool,
+}
+
+impl InputBuffer {
+    /// Buffers the inputs that were not simulated yet, the redundant ones are ignored.
+    pub fn extend(&mut self, inputs: Vec<ClientInput>) {
+        for input in inputs {
+            if input.sequence <= self.last_consumed {
+                continue;
+            }
+
+            let index = self.inputs.partition_point(|i| i.sequence < input.sequence);
+
+            if self
+                .inputs
+                .get(index)
+                .is_some_and(|i| i.sequence == input.sequence)
+            {
+                continue;
+            }
+
+            self.inputs.insert(index, input);
+        }
+
+        while self.inputs.len() > MAX_BUFFERED_INPUTS {
+            if let Some(dropped) = self.inputs.pop_front() {
+                self.last_consumed = dropped.sequence;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn next(&mut self) -> Option<ClientInput> {
+        if !self.playing {
+            if self.inputs.len() < JITTER_BUFFER_TICKS {
+                return None;
+            }
+
+            self.playing = true;
+        }
+
+        let Some(input) = self.inputs.pop_front() else {
+            // Ran dry, build the cushion up again before resuming.
+            self.playing = false;
+            return None;
+        };
+
+        self.last_consumed = input.sequence;
+        Some(input)
+    }
+}
+
+/// Applies exactly one buffered input per tick, a starved player keeps repeating its last one.
+pub fn consume_player_inputs(mut players: Query<(&mut InputBuffer, &mut ClientInput)>) {
+    for (mut buffer, mut input) in players.iter_mut() {
+        if let Some(next) = buffer.next() {
+            *input = next;
+        }
+    }
+}
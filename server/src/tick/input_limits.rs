@@ -0,0 +1,212 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_renet2::prelude::ClientId;
+use common::{CameraInput, Client, ClientInput, MAX_CAMERA_PITCH, MAX_CAMERA_ROLL};
+
+use crate::violations::ProtocolViolations;
+
+/// Messages a client may send per tick on average: its input packet, with headroom for the rest.
+const MESSAGES_PER_TICK: f32 = 1.5;
+/// Messages a client may send at once, e.g. when its link catches up after a stall.
+const MESSAGE_BURST: f32 = 32.0;
+/// Fastest a player may keep turning, in radians per second.
+const MAX_TURN_RATE: f32 = 12.0 * TAU;
+/// Turn a player may make at once, any yaw is at most half a turn away.
+const MAX_TURN_BURST: f32 = PI;
+/// Slack for angles the client clamped itself.
+const ANGLE_TOLERANCE: f32 = 1e-3;
+/// How far past its limit an angle is clamped without a report, e.g. from a client rounding
+/// differently. Anything further is no mouse movement.
+const PLAUSIBLE_ANGLE_EXCESS: f32 = 0.1;
+/// Seconds the camera may trail what the client asks for before it is reported, long enough to
+/// catch up with a flick or a burst of inputs after a stall.
+const TURN_TRAIL_GRACE: f32 = 0.5;
+
+/// Per-client allowance of incoming messages, refilled every tick.
+#[derive(Debug, Default, Resource)]
+pub struct MessageBudgets(HashMap<ClientId, MessageBudget>);
+
+#[derive(Debug)]
+struct MessageBudget {
+    tokens: f32,
+    refilled_at: u32,
+    /// Tick the client was last reported for exceeding its budget.
+    reported_at: Option<u32>,
+}
+
+/// Outcome of charging a message to a client's budget.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Charge {
+    Accepted,
+    /// Over budget, the message is dropped. Reported once per `report_interval` ticks of flooding,
+    /// so a client that keeps it up gets kicked.
+    Dropped {
+        report: bool,
+    },
+}
+
+impl MessageBudgets {
+    pub fn charge(&mut self, client_id: ClientId, tick: u32, report_interval: u32) -> Charge {
+        let budget = self.0.entry(client_id).or_insert(MessageBudget {
+            tokens: MESSAGE_BURST,
+            refilled_at: tick,
+            reported_at: None,
+        });
+
+        let refill = tick.wrapping_sub(budget.refilled_at) as f32 * MESSAGES_PER_TICK;
+        budget.tokens = (budget.tokens + refill).min(MESSAGE_BURST);
+        budget.refilled_at = tick;
+
+        if budget.tokens >= 1.0 {
+            budget.tokens -= 1.0;
+            return Charge::Accepted;
+        }
+
+        let report = budget
+            .reported_at
+            .is_none_or(|reported_at| tick.wrapping_sub(reported_at) >= report_interval);
+
+        if report {
+            budget.reported_at = Some(tick);
+        }
+
+        Charge::Dropped { report }
+    }
+
+    pub fn forget(&mut self, client_id: ClientId) {
+        self.0.remove(&client_id);
+    }
+}
+
+/// Verdict on an input the client claims to have sampled, from least to most severe.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InputCheck {
+    Valid,
+    /// Slightly out of range, clamped in place without a report.
+    Corrected,
+    /// Out of range but usable once clamped, which was done in place.
+    Clamped(&'static str),
+    /// Nothing sensible can be made of it.
+    Rejected(&'static str),
+}
+
+/// Checks an input against what the client itself enforces. The weapon needs no check, decoding
+/// already rejects unknown variants.
+pub fn check_input(input: &mut ClientInput) -> InputCheck {
+    let camera = &mut input.camera;
+
+    if !(camera.pitch.is_finite() && camera.yaw.is_finite() && camera.roll.is_finite()) {
+        return InputCheck::Rejected("non-finite camera angle");
+    }
+
+    let pitch = clamp_angle(
+        &mut camera.pitch,
+        MAX_CAMERA_PITCH,
+        "camera pitch out of range",
+    );
+    let roll = clamp_angle(
+        &mut camera.roll,
+        MAX_CAMERA_ROLL,
+        "camera roll out of range",
+    );
+
+    pitch.max(roll)
+}
+
+fn clamp_angle(angle: &mut f32, max: f32, reason: &'static str) -> InputCheck {
+    let excess = angle.abs() - max;
+    *angle = angle.clamp(-max, max);
+
+    if excess > PLAUSIBLE_ANGLE_EXCESS {
+        InputCheck::Clamped(reason)
+    } else if excess > ANGLE_TOLERANCE {
+        InputCheck::Corrected
+    } else {
+        InputCheck::Valid
+    }
+}
+
+/// How far a player may still turn the camera, refilled at [`MAX_TURN_RATE`] up to
+/// [`MAX_TURN_BURST`]. A flick of any speed fits, spinning faster than the rate for long doesn't.
+#[derive(Debug, Component)]
+pub struct TurnLimit {
+    budget: f32,
+    /// The camera as last applied, which may trail what the client asks for. Unknown until the
+    /// first input, the client picks where it looks on spawn.
+    camera: Option<CameraInput>,
+    /// Seconds the camera has been trailing without catching up.
+    trailing: f32,
+}
+
+impl Default for TurnLimit {
+    fn default() -> Self {
+        Self {
+            budget: MAX_TURN_BURST,
+            camera: None,
+            trailing: 0.0,
+        }
+    }
+}
+
+impl TurnLimit {
+    /// Turns towards `requested` as far as the budget allows, `false` if that is not all the way.
+    fn turn(&mut self, requested: &CameraInput, delta: f32) -> (CameraInput, bool) {
+        let Some(camera) = self.camera else {
+            self.camera = Some(*requested);
+            return (*requested, true);
+        };
+
+        self.budget = (self.budget + MAX_TURN_RATE * delta).min(MAX_TURN_BURST);
+
+        let yaw = yaw_difference(camera.yaw, requested.yaw);
+        let pitch = requested.pitch - camera.pitch;
+        let needed = yaw.abs().max(pitch.abs());
+        let step = needed.min(self.budget);
+
+        self.budget -= step;
+        let camera = CameraInput {
+            yaw: camera.yaw + yaw.clamp(-step, step),
+            pitch: camera.pitch + pitch.clamp(-step, step),
+            roll: requested.roll,
+        };
+        self.camera = Some(camera);
+
+        (camera, needed <= step)
+    }
+}
+
+/// Signed yaw from `from` to `to`, the short way around.
+fn yaw_difference(from: f32, to: f32) -> f32 {
+    (to - from + PI).rem_euclid(TAU) - PI
+}
+
+/// Holds every player's camera to [`MAX_TURN_RATE`], reporting those that keep asking to turn
+/// faster for longer than [`TURN_TRAIL_GRACE`].
+pub fn limit_turn_rate(
+    time: Res<Time>,
+    mut violations: ResMut<ProtocolViolations>,
+    mut players: Query<(&Client, &mut TurnLimit, &mut ClientInput)>,
+) {
+    for (client, mut limit, mut input) in players.iter_mut() {
+        // Nothing received yet, client sequences start at 1.
+        if input.sequence == 0 {
+            continue;
+        }
+
+        let (camera, within) = limit.turn(&input.camera, time.delta_secs());
+
+        if within {
+            limit.trailing = 0.0;
+        } else {
+            limit.trailing += time.delta_secs();
+
+            if limit.trailing >= TURN_TRAIL_GRACE {
+                violations.report(client.id, "turned faster than possible");
+                limit.trailing = 0.0;
+            }
+        }
+
+        input.camera = camera;
+    }
+}
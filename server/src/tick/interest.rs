@@ -0,0 +1,225 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_rapier3d::prelude::*;
+use bevy_renet2::prelude::ClientId;
+use common::{
+    Client, WorldSnapshot,
+    delta::{ClientDataDelta, ProjectileDataDelta, SnapshotHistory},
+    replication::{Replicate, ReplicationInterest},
+};
+
+use super::line_of_sight::LineOfSight;
+use crate::config::ServerConfig;
+
+/// Most players a single snapshot carries, besides the receiver itself.
+const MAX_SNAPSHOT_PLAYERS: usize = 32;
+const MAX_SNAPSHOT_PROJECTILES: usize = 64;
+/// Fraction of the relevance radius an entity that changed since the receiver's baseline is
+/// moved ahead of unchanged ones.
+const CHANGED_PRIORITY: f32 = 0.5;
+
+/// Snapshots sent to one client, the baselines its acknowledgements refer to.
+#[derive(Debug, Default, Component)]
+pub struct SentSnapshots(pub SnapshotHistory);
+
+/// Uniform grid over the ground plane, bucketing entities by the cell they stand in.
+#[derive(Debug)]
+struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<(usize, Vec3)>>,
+}
+
+impl SpatialGrid {
+    fn new(cell_size: f32, positions: impl Iterator<Item = Vec3>) -> Self {
+        let mut grid = Self {
+            cell_size,
+            cells: HashMap::new(),
+        };
+
+        for (index, position) in positions.enumerate() {
+            let cell = grid.cell(position);
+            grid.cells.entry(cell).or_default().push((index, position));
+        }
+
+        grid
+    }
+
+    fn cell(&self, position: Vec3) -> IVec2 {
+        (position.xz() / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Entities within `radius` of `center`, with their distance to it.
+    fn within(&self, center: Vec3, radius: f32) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let min = self.cell(center - Vec3::splat(radius));
+        let max = self.cell(center + Vec3::splat(radius));
+
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .map(move |&(index, position)| (index, position.distance(center)))
+            .filter(move |&(_, distance)| distance <= radius)
+    }
+}
+
+/// Spatial index of everything a [`WorldSnapshot`] holds, built once per snapshot.
+#[derive(Debug)]
+pub struct WorldIndex<'a> {
+    snapshot: &'a WorldSnapshot,
+    players: SpatialGrid,
+    projectiles: SpatialGrid,
+}
+
+impl<'a> WorldIndex<'a> {
+    /// Cells span the relevance radius, so a query touches at most three by three of them.
+    pub fn new(snapshot: &'a WorldSnapshot, radius: f32) -> Self {
+        Self {
+            snapshot,
+            players: SpatialGrid::new(radius, snapshot.players.iter().map(|p| p.pos.into())),
+            projectiles: SpatialGrid::new(
+                radius,
+                snapshot.projectiles.iter().map(|p| p.pos.into()),
+            ),
+        }
+    }
+
+    /// The part of the world relevant to `viewer`, standing at `center`.
+    ///
+    /// Entities are ranked by distance, with the ones that changed since `baseline` moved ahead,
+    /// and cut off at a fixed budget. The viewer itself is always included, other players only
+    /// if `may_see` them.
+    pub fn relevant_to(
+        &self,
+        viewer: ClientId,
+        center: Vec3,
+        radius: f32,
+        baseline: Option<&WorldSnapshot>,
+        mut may_see: impl FnMut(ClientId) -> bool,
+    ) -> WorldSnapshot {
+        let snapshot = self.snapshot;
+        let base_players = baseline.map_or(&[][..], |b| b.players.as_slice());
+        let base_projectiles = baseline.map_or(&[][..], |b| b.projectiles.as_slice());
+
+        let mut players: Vec<_> = snapshot
+            .players
+            .iter()
+            .filter(|player| player.id == viewer)
+            .cloned()
+            .collect();
+
+        let others = self
+            .players
+            .within(center, radius)
+            .filter(|&(index, _)| snapshot.players[index].id != viewer)
+            .filter(|&(index, _)| may_see(snapshot.players[index].id));
+
+        players.extend(
+            prioritize(others, radius, MAX_SNAPSHOT_PLAYERS, |index| {
+                let player = &snapshot.players[index];
+                let old = base_players.iter().find(|old| old.id == player.id);
+                ClientDataDelta::between(old, player).is_some()
+            })
+            .map(|index| snapshot.players[index].clone()),
+        );
+
+        let projectiles = prioritize(
+            self.projectiles.within(center, radius),
+            radius,
+            MAX_SNAPSHOT_PROJECTILES,
+            |index| {
+                let projectile = &snapshot.projectiles[index];
+                let old = base_projectiles.iter().find(|old| old.id == projectile.id);
+                ProjectileDataDelta::between(old, projectile).is_some()
+            },
+        )
+        .map(|index| snapshot.projectiles[index].clone())
+        .collect();
+
+        WorldSnapshot {
+            tick: snapshot.tick,
+            players,
+            projectiles,
+            private: None,
+        }
+    }
+}
+
+/// The `budget` most relevant of `candidates`, nearest first.
+///
+/// An entity left out is missing from the receiver's next baseline, so it counts as changed and
+/// moves up on the following snapshot.
+fn prioritize(
+    candidates: impl Iterator<Item = (usize, f32)>,
+    radius: f32,
+    budget: usize,
+    changed: impl Fn(usize) -> bool,
+) -> impl Iterator<Item = usize> {
+    let mut ranked: Vec<(usize, f32)> = candidates
+        .map(|(index, distance)| {
+            let bonus = if changed(index) {
+                CHANGED_PRIORITY
+            } else {
+                0.0
+            };
+            (index, distance / radius - bonus)
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+    ranked.truncate(budget);
+    ranked.into_iter().map(|(index, _)| index)
+}
+
+/// Lists the replicated entities within `interest_radius` of each player, indexed on the same
+/// grid as snapshots, so their [`Audience::Interest`](common::replication::Audience::Interest)
+/// components reach the same players. Other players also have to be in sight, as in snapshots.
+pub fn update_replication_interest(
+    config: Res<ServerConfig>,
+    mut interest: ResMut<ReplicationInterest>,
+    mut line_of_sight: ResMut<LineOfSight>,
+    rapier_context: ReadRapierContext,
+    players: Query<(&Client, &Transform)>,
+    replicated: Query<(Entity, &Transform, Option<&Client>), With<Replicate>>,
+) {
+    let entities: Vec<(Entity, Vec3, Option<ClientId>)> = replicated
+        .iter()
+        .map(|(entity, transform, client)| {
+            (
+                entity,
+                transform.translation,
+                client.map(|client| client.id),
+            )
+        })
+        .collect();
+    let grid = SpatialGrid::new(
+        config.interest_radius,
+        entities.iter().map(|&(_, position, _)| position),
+    );
+
+    let rapier_context = rapier_context
+        .single()
+        .expect("Default Rapier context to exist");
+    let is_not_player = |entity| !players.contains(entity);
+    let filter = QueryFilter::new()
+        .exclude_sensors()
+        .predicate(&is_not_player);
+
+    interest.0 = players
+        .iter()
+        .map(|(client, transform)| {
+            let relevant = grid
+                .within(transform.translation, config.interest_radius)
+                .map(|(index, _)| entities[index])
+                .filter(|&(_, _, player)| match player {
+                    Some(target) => {
+                        target == client.id
+                            || line_of_sight.visible(&rapier_context, filter, client.id, target)
+                    }
+                    None => true,
+                })
+                .map(|(entity, _, _)| entity)
+                .collect();
+
+            (client.id, relevant)
+        })
+        .collect();
+}
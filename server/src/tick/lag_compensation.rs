@@ -0,0 +1,121 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+use common::{Client, MovementState, TickRate, movement::player_collider};
+
+use super::{Health, WorldState};
+use crate::config::ServerConfig;
+
+/// How far back in time shots may be resolved against player colliders.
+#[derive(Debug, Resource)]
+pub struct LagCompensation {
+    pub max_rewind: Duration,
+}
+
+impl LagCompensation {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            max_rewind: Duration::from_millis(config.max_rewind_ms),
+        }
+    }
+
+    fn max_rewind_ticks(&self, tick_rate: &TickRate) -> u32 {
+        (self.max_rewind.as_secs_f64() * tick_rate.0).ceil() as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerPose {
+    entity: Entity,
+    translation: Vec3,
+    crouched: bool,
+    alive: bool,
+}
+
+#[derive(Debug)]
+struct PoseFrame {
+    tick: u32,
+    poses: Vec<PlayerPose>,
+}
+
+/// Ring buffer of player collider poses, one frame per simulated tick.
+#[derive(Debug, Default, Resource)]
+pub struct PoseHistory {
+    frames: VecDeque<PoseFrame>,
+}
+
+impl PoseHistory {
+    /// Player poses as they were at `view_tick`, clamped to the configured rewind window.
+    pub fn poses_at(
+        &self,
+        view_tick: u32,
+        current_tick: u32,
+        lag_compensation: &LagCompensation,
+        tick_rate: &TickRate,
+    ) -> Option<&[PlayerPose]> {
+        let rewind = current_tick
+            .checked_sub(view_tick)?
+            .min(lag_compensation.max_rewind_ticks(tick_rate));
+        let tick = current_tick - rewind;
+
+        self.frames
+            .iter()
+            .find(|frame| frame.tick == tick)
+            .map(|frame| frame.poses.as_slice())
+    }
+}
+
+pub fn record_player_poses(
+    world_state: Res<WorldState>,
+    lag_compensation: Res<LagCompensation>,
+    tick_rate: Res<TickRate>,
+    mut history: ResMut<PoseHistory>,
+    players: Query<(Entity, &Transform, &MovementState, &Health), With<Client>>,
+) {
+    let poses = players
+        .iter()
+        .map(|(entity, transform, movement, health)| PlayerPose {
+            entity,
+            translation: transform.translation,
+            crouched: movement.crouched,
+            alive: health.current > 0.0,
+        })
+        .collect();
+
+    history.frames.push_back(PoseFrame {
+        tick: world_state.tick,
+        poses,
+    });
+
+    let capacity = lag_compensation.max_rewind_ticks(&tick_rate) as usize + 1;
+
+    while history.frames.len() > capacity {
+        history.frames.pop_front();
+    }
+}
+
+/// Casts a ray against rewound player colliders, returning the closest hit player and time of impact.
+pub fn cast_ray_rewound(
+    poses: &[PlayerPose],
+    exclude: Entity,
+    origin: Vec3,
+    direction: Vec3,
+    max_time_of_impact: f32,
+) -> Option<(Entity, f32)> {
+    poses
+        .iter()
+        .filter(|pose| pose.alive && pose.entity != exclude)
+        .filter_map(|pose| {
+            player_collider(pose.crouched)
+                .cast_ray(
+                    pose.translation,
+                    Quat::IDENTITY,
+                    origin,
+                    direction,
+                    max_time_of_impact,
+                    true,
+                )
+                .map(|time_of_impact| (pose.entity, time_of_impact))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
@@ -0,0 +1,139 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_rapier3d::prelude::*;
+use bevy_renet2::prelude::ClientId;
+use common::{
+    Client, MovementState, PLAYER_COLLIDER_HALF_HEIGHT, PLAYER_COLLIDER_RADIUS,
+    PLAYER_CROUCH_VIEW_OFFSET, PLAYER_RUN_SPEED,
+};
+
+/// Seconds of movement enemies are sent ahead of, so they are already there when they come into
+/// view instead of popping in a round trip later.
+const LEAD_SECONDS: f32 = 0.25;
+/// Distance a target can cover within the lead, added around its collider.
+const LEAD_MARGIN: f32 = PLAYER_RUN_SPEED * LEAD_SECONDS;
+/// Enemies this close can be heard moving, walls or not.
+const HEARING_RADIUS: f32 = 8.0;
+/// Cells shots out of sight are snapped to, enough to tell where the shot came from without
+/// pinpointing the shooter.
+const SHOT_POSITION_CELL: f32 = 8.0;
+
+/// Where a player looks from and how it moves, as far as line of sight is concerned.
+#[derive(Debug, Clone, Copy)]
+pub struct Sightline {
+    pub eye: Vec3,
+    pub center: Vec3,
+    pub velocity: Vec3,
+}
+
+impl Sightline {
+    pub fn new(translation: Vec3, velocity: Vec3, crouched: bool) -> Self {
+        let view_offset = if crouched {
+            PLAYER_CROUCH_VIEW_OFFSET
+        } else {
+            0.0
+        };
+
+        Self {
+            eye: translation + Vec3::Y * view_offset,
+            center: translation,
+            velocity,
+        }
+    }
+}
+
+/// Every player's sightline on the current tick, and the line of sight checks done so far.
+///
+/// Snapshots and game events ask about the same pairs, each is only cast once per tick.
+#[derive(Debug, Default, Resource)]
+pub struct LineOfSight {
+    sightlines: HashMap<ClientId, Sightline>,
+    /// By viewer then target.
+    visible: HashMap<(ClientId, ClientId), bool>,
+}
+
+impl LineOfSight {
+    /// Whether `viewer` could see or hear `target`, see [`potentially_visible`]. Players that are
+    /// gone can't.
+    pub fn visible(
+        &mut self,
+        context: &RapierContext,
+        filter: QueryFilter,
+        viewer: ClientId,
+        target: ClientId,
+    ) -> bool {
+        let (Some(viewer_sightline), Some(target_sightline)) =
+            (self.sightlines.get(&viewer), self.sightlines.get(&target))
+        else {
+            return false;
+        };
+
+        *self.visible.entry((viewer, target)).or_insert_with(|| {
+            potentially_visible(context, filter, viewer_sightline, target_sightline)
+        })
+    }
+}
+
+/// Takes every player's sightline once the tick is simulated, forgetting the previous tick's.
+pub fn update_line_of_sight(
+    mut line_of_sight: ResMut<LineOfSight>,
+    players: Query<(&Client, &Transform, &MovementState)>,
+) {
+    line_of_sight.visible.clear();
+    line_of_sight.sightlines = players
+        .iter()
+        .map(|(client, transform, movement)| {
+            let sightline =
+                Sightline::new(transform.translation, movement.velocity, movement.crouched);
+
+            (client.id, sightline)
+        })
+        .collect();
+}
+
+/// Where a shot out of the listener's sight is said to come from.
+pub fn coarse_shot_position(pos: Vec3) -> Vec3 {
+    ((pos / SHOT_POSITION_CELL).floor() + 0.5) * SHOT_POSITION_CELL
+}
+
+/// Conservative check of whether `viewer` could see or hear `target` within the lead time.
+///
+/// Rays go from where the viewer's eye is and will be to the corners of the target's collider,
+/// grown by how far it can run meanwhile. Only the world blocks them, `filter` has to leave
+/// players out. A single clear ray is enough, so this errs on the side of sending.
+pub fn potentially_visible(
+    context: &RapierContext,
+    filter: QueryFilter,
+    viewer: &Sightline,
+    target: &Sightline,
+) -> bool {
+    if viewer.center.distance(target.center) <= HEARING_RADIUS {
+        return true;
+    }
+
+    let eyes = [viewer.eye, viewer.eye + viewer.velocity * LEAD_SECONDS];
+    let extents = Vec3::new(
+        PLAYER_COLLIDER_RADIUS + LEAD_MARGIN,
+        PLAYER_COLLIDER_HALF_HEIGHT + PLAYER_COLLIDER_RADIUS + LEAD_MARGIN,
+        PLAYER_COLLIDER_RADIUS + LEAD_MARGIN,
+    );
+    let corners = [-1.0, 1.0].into_iter().flat_map(|x| {
+        [-1.0, 1.0]
+            .into_iter()
+            .flat_map(move |y| [-1.0, 1.0].map(move |z| Vec3::new(x, y, z)))
+    });
+    let points = std::iter::once(target.center)
+        .chain(corners.map(|corner| target.center + corner * extents))
+        .collect::<Vec<_>>();
+
+    eyes.iter().any(|&eye| {
+        points.iter().any(|&point| {
+            let offset = point - eye;
+            let distance = offset.length();
+
+            distance <= f32::EPSILON
+                || context
+                    .cast_ray(eye, offset / distance, distance, true, filter)
+                    .is_none()
+        })
+    })
+}
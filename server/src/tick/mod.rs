@@ -1,35 +1,111 @@
-use bevy::prelude::*;
+use std::collections::VecDeque;
+
+use bevy::{platform::collections::HashMap, prelude::*};
 use bevy_rapier3d::prelude::*;
 
-use bevy_renet2::prelude::{DefaultChannel, RenetServer, ServerEvent};
-use common::*;
+use bevy_renet2::{
+    netcode::{NetcodeServerTransport, NetcodeTransportError},
+    prelude::{ClientId, RenetServer, ServerEvent},
+};
+use common::{
+    data::{Channel, Compression, DecodeError},
+    delta::SnapshotDelta,
+    movement::{
+        player_collider, player_collision_groups, set_crouched_state, step_player_movement,
+    },
+    replication::{NetworkOwner, Replicate, send_replication},
+    *,
+};
+
+use crate::{config::ServerConfig, violations::ProtocolViolations};
+
+mod events;
+mod input_buffer;
+mod input_limits;
+mod interest;
+mod lag_compensation;
+mod line_of_sight;
+mod send_rate;
+
+use events::{PendingEvents, send_game_events};
+use input_buffer::{InputBuffer, consume_player_inputs};
+use input_limits::{Charge, InputCheck, MessageBudgets, TurnLimit, check_input, limit_turn_rate};
+use interest::{SentSnapshots, WorldIndex, update_replication_interest};
+use lag_compensation::{LagCompensation, PoseHistory, cast_ray_rewound, record_player_poses};
+use line_of_sight::{LineOfSight, update_line_of_sight};
+use send_rate::{SnapshotPacing, adapt_snapshot_rates};
 
 pub struct Plugin;
 
 impl bevy::prelude::Plugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Time::<Fixed>::from_hz(128.0))
+        let tick_rate = app.world().resource::<TickRate>().0;
+        let lag_compensation = LagCompensation::new(app.world().resource::<ServerConfig>());
+
+        app.insert_resource(Time::<Fixed>::from_hz(tick_rate))
             .init_resource::<WorldState>()
-            .add_systems(Startup, spawn_world_colliders)
+            .insert_resource(lag_compensation)
+            .init_resource::<PoseHistory>()
+            .init_resource::<PendingHandshakes>()
+            .init_resource::<PendingEvents>()
+            .init_resource::<LineOfSight>()
+            .init_resource::<MessageBudgets>()
+            .add_systems(FixedUpdate, advance_tick.before(recv_players_input))
+            .add_systems(Update, log_transport_errors)
             .add_systems(FixedUpdate, recv_connectivity)
+            .add_systems(FixedUpdate, expire_handshakes.after(recv_connectivity))
             .add_systems(FixedUpdate, recv_players_input)
-            .add_systems(FixedUpdate, respawn_tick.after(recv_players_input))
+            .add_systems(FixedUpdate, consume_player_inputs.after(recv_players_input))
+            .add_systems(FixedUpdate, limit_turn_rate.after(consume_player_inputs))
+            .add_systems(FixedUpdate, respawn_tick.after(limit_turn_rate))
             .add_systems(FixedUpdate, physx_tick.after(respawn_tick))
             .add_systems(FixedUpdate, weapons_tick.after(physx_tick))
             .add_systems(FixedUpdate, projectiles_tick.after(weapons_tick))
-            .add_systems(PostUpdate, sync_ground_state)
-            .add_systems(FixedUpdate, send_world_snapshot.after(projectiles_tick));
+            .add_systems(FixedUpdate, record_player_poses.after(projectiles_tick))
+            .add_systems(FixedUpdate, update_line_of_sight.after(projectiles_tick))
+            .add_systems(FixedUpdate, send_game_events.after(update_line_of_sight))
+            .add_systems(
+                FixedUpdate,
+                update_replication_interest.after(update_line_of_sight),
+            )
+            .add_systems(FixedUpdate, update_player_state.after(projectiles_tick))
+            .add_systems(
+                FixedUpdate,
+                send_replication
+                    .after(update_replication_interest)
+                    .after(update_player_state),
+            )
+            .add_systems(
+                FixedUpdate,
+                adapt_snapshot_rates
+                    .before(send_world_snapshot)
+                    .run_if(|config: Res<ServerConfig>| config.adaptive_snapshot_rate),
+            )
+            .add_systems(
+                FixedUpdate,
+                send_world_snapshot
+                    .after(record_player_poses)
+                    .after(update_line_of_sight)
+                    .run_if(snapshot_due),
+            );
     }
 }
 
 #[derive(Debug, Default, Resource)]
 struct WorldState {
+    tick: u32,
     next_projectile_id: u64,
-    next_mark_id: u64,
-    impact_marks: Vec<ImpactMarkData>,
-    fired_projectiles: Vec<FiredProjectileData>,
+    /// Every mark still in the world, oldest first.
+    impact_marks: VecDeque<Entity>,
 }
 
+/// Seconds a connected client has to send its [`Hello`].
+const HANDSHAKE_TIMEOUT: f32 = 5.0;
+
+/// Connected clients that did not complete the handshake yet, with their connection time.
+#[derive(Debug, Default, Resource)]
+struct PendingHandshakes(HashMap<ClientId, f32>);
+
 #[derive(Debug, Component)]
 struct Health {
     current: f32,
@@ -72,24 +148,19 @@ struct Projectile {
     damage: f32,
     lifetime: f32,
     owner_entity: Entity,
+    owner_id: ClientId,
+    /// Server tick the shooter was looking at, consumed when resolving the first segment.
+    rewind_tick: Option<u32>,
 }
 
-fn spawn_world_colliders(mut commands: Commands) {
-    commands.spawn(Collider::cuboid(10.0, 0.1, 10.0));
-
-    commands.spawn((
-        Collider::cuboid(1.0, 0.25, 0.5),
-        Transform::from_xyz(0.0, 0.25, -3.0),
-    ));
-
-    commands.spawn((
-        Collider::cuboid(1.0, 0.25, 0.5),
-        Transform::from_xyz(0.75, 1.75, 0.0),
-    ));
+fn advance_tick(mut world_state: ResMut<WorldState>) {
+    world_state.tick = world_state.tick.wrapping_add(1);
 }
 
 fn respawn_tick(
+    mut events: ResMut<PendingEvents>,
     mut query: Query<(
+        &Client,
         &ClientInput,
         &mut Health,
         &mut Arsenal,
@@ -98,7 +169,9 @@ fn respawn_tick(
         &mut Transform,
     )>,
 ) {
-    for (input, mut health, mut arsenal, mut movement, mut collider, mut transform) in query.iter_mut() {
+    for (client, input, mut health, mut arsenal, mut movement, mut collider, mut transform) in
+        query.iter_mut()
+    {
         if health.current > 0.0 {
             arsenal.last_respawn_sequence = input.respawn_sequence;
             continue;
@@ -120,6 +193,7 @@ fn respawn_tick(
 
         set_crouched_state(&mut movement, &mut collider, &mut transform, false);
         transform.translation = Vec3::new(0.0, PLAYER_RESPAWN_HEIGHT, 0.0);
+        events.push(GameEvent::PlayerRespawned { id: client.id });
     }
 }
 
@@ -131,8 +205,6 @@ fn physx_tick(
         &Health,
         &mut MovementState,
         &mut Collider,
-        &mut KinematicCharacterController,
-        Option<&KinematicCharacterControllerOutput>,
         &mut Transform,
     )>,
     time: Res<Time>,
@@ -142,97 +214,33 @@ fn physx_tick(
         .expect("Default Rapier context to exist");
     let delta = time.delta_secs();
 
-    for (entity, input, health, mut movement, mut collider, mut controller, output, mut transform) in
-        query.iter_mut()
-    {
+    for (entity, input, health, mut movement, mut collider, mut transform) in query.iter_mut() {
         if health.current <= 0.0 {
             movement.velocity = Vec3::ZERO;
-            controller.translation = Some(Vec3::ZERO);
             continue;
         }
 
-        let wants_to_crouch = input.crouch;
-
-        if wants_to_crouch && !movement.crouched {
-            set_crouched_state(&mut movement, &mut collider, &mut transform, true);
-        } else if !wants_to_crouch
-            && movement.crouched
-            && can_stand_up(entity, &rapier_context, transform.translation)
-        {
-            set_crouched_state(&mut movement, &mut collider, &mut transform, false);
-        }
-
-        let x = (input.right as i8 - input.left as i8) as f32;
-        let z = (input.backward as i8 - input.forward as i8) as f32;
-
-        let local_input = Vec3::new(x, 0.0, z).normalize_or_zero();
-        let yaw_rotation = Quat::from_rotation_y(input.camera.yaw);
-        let wish_dir = yaw_rotation * local_input;
-
-        let horizontal_velocity = Vec3::new(movement.velocity.x, 0.0, movement.velocity.z);
-        let target_speed = if movement.crouched {
-            PLAYER_CROUCH_SPEED
-        } else if input.run {
-            PLAYER_RUN_SPEED
-        } else {
-            PLAYER_WALK_SPEED
-        };
-        let target_horizontal_velocity = wish_dir * target_speed;
-
-        let grounded = output.is_some_and(|output| output.grounded);
-        movement.grounded = grounded;
-
-        let horizontal_delta = target_horizontal_velocity - horizontal_velocity;
-
-        let accel = if grounded {
-            if local_input == Vec3::ZERO {
-                PLAYER_GROUND_DECELERATION
-            } else {
-                PLAYER_GROUND_ACCELERATION
-            }
-        } else if local_input == Vec3::ZERO {
-            0.0
-        } else {
-            PLAYER_AIR_ACCELERATION
-        };
-
-        let accel_factor = (accel * delta).min(1.0);
-        let air_control = if grounded { 1.0 } else { PLAYER_AIR_CONTROL };
-        let next_horizontal_velocity =
-            horizontal_velocity + horizontal_delta * accel_factor * air_control;
-
-        movement.velocity.x = next_horizontal_velocity.x;
-        movement.velocity.z = next_horizontal_velocity.z;
-
-        if grounded {
-            if movement.velocity.y < 0.0 {
-                movement.velocity.y = 0.0;
-            }
-
-            if input.jump && !movement.jump_queued {
-                movement.velocity.y = PLAYER_JUMP_SPEED;
-                movement.grounded = false;
-                movement.jump_queued = true;
-            }
-        } else {
-            movement.velocity.y -= PLAYER_GRAVITY * delta;
-        }
-
-        if !input.jump {
-            movement.jump_queued = false;
-        }
-
-        controller.translation = Some(movement.velocity * delta);
-        transform.rotation = Quat::from_rotation_y(input.camera.yaw);
+        step_player_movement(
+            entity,
+            &rapier_context,
+            input,
+            &mut movement,
+            &mut collider,
+            &mut transform,
+            delta,
+        );
     }
 }
 
+#[allow(clippy::collapsible_if)]
 fn weapons_tick(
     mut commands: Commands,
     time: Res<Time>,
     mut world_state: ResMut<WorldState>,
+    mut events: ResMut<PendingEvents>,
     mut query: Query<(
         Entity,
+        &Client,
         &ClientInput,
         &Transform,
         &MovementState,
@@ -242,9 +250,7 @@ fn weapons_tick(
 ) {
     let now = time.elapsed_secs();
     let delta = time.delta_secs();
-    world_state.fired_projectiles.clear();
-
-    for (entity, input, transform, movement, health, mut arsenal) in query.iter_mut() {
+    for (entity, client, input, transform, movement, health, mut arsenal) in query.iter_mut() {
         if health.current <= 0.0 {
             continue;
         }
@@ -321,31 +327,44 @@ fn weapons_tick(
                 damage: spec.damage,
                 lifetime: PROJECTILE_LIFETIME,
                 owner_entity: entity,
+                owner_id: client.id,
+                rewind_tick: input.view_tick,
             },
             Transform::from_translation(muzzle_origin),
         ));
 
-        world_state.fired_projectiles.push(FiredProjectileData {
-            id: projectile_id,
+        events.push(GameEvent::ShotFired {
+            projectile_id,
+            shooter: client.id,
             weapon: active_weapon,
+            pos: muzzle_origin.into(),
         });
         world_state.next_projectile_id = world_state.next_projectile_id.wrapping_add(1);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn projectiles_tick(
     mut commands: Commands,
     mut world_state: ResMut<WorldState>,
+    mut events: ResMut<PendingEvents>,
     rapier_context: ReadRapierContext,
+    lag_compensation: Res<LagCompensation>,
+    tick_rate: Res<TickRate>,
+    pose_history: Res<PoseHistory>,
     time: Res<Time>,
     mut projectiles: Query<(Entity, &mut Projectile, &mut Transform)>,
     mut players: Query<
-        (Entity, &mut Health, &mut MovementState, &mut Collider, &mut Transform),
-        (With<Client>, Without<Projectile>),
+        (
+            &Client,
+            &mut Health,
+            &mut MovementState,
+            &mut Collider,
+            &mut Transform,
+        ),
+        Without<Projectile>,
     >,
 ) {
-    const MAX_IMPACT_MARKS: usize = 256;
-
     let rapier_context = rapier_context
         .single()
         .expect("Default Rapier context to exist");
@@ -362,38 +381,97 @@ fn projectiles_tick(
             let filter = QueryFilter::new()
                 .exclude_collider(projectile.owner_entity)
                 .exclude_sensors();
+            let rewound_poses = projectile.rewind_tick.take().and_then(|view_tick| {
+                pose_history.poses_at(view_tick, world_state.tick, &lag_compensation, &tick_rate)
+            });
+
+            let hit = if let Some(poses) = rewound_poses {
+                // Only the world is tested live, players are tested where the shooter saw them.
+                let is_not_player = |entity| !players.contains(entity);
+                let world_hit = rapier_context.cast_ray_and_get_normal(
+                    start,
+                    direction,
+                    distance,
+                    true,
+                    filter.predicate(&is_not_player),
+                );
+                let max_time_of_impact = world_hit
+                    .as_ref()
+                    .map_or(distance, |(_, hit)| hit.time_of_impact);
+
+                match cast_ray_rewound(
+                    poses,
+                    projectile.owner_entity,
+                    start,
+                    direction,
+                    max_time_of_impact,
+                ) {
+                    Some((player_entity, time_of_impact)) => Some((
+                        player_entity,
+                        start + direction * time_of_impact,
+                        -direction,
+                    )),
+                    None => world_hit.map(|(hit_entity, hit)| (hit_entity, hit.point, hit.normal)),
+                }
+            } else {
+                rapier_context
+                    .cast_ray_and_get_normal(start, direction, distance, true, filter)
+                    .map(|(hit_entity, hit)| (hit_entity, hit.point, hit.normal))
+            };
 
-            if let Some((hit_entity, hit)) =
-                rapier_context.cast_ray_and_get_normal(start, direction, distance, true, filter)
-            {
+            if let Some((hit_entity, hit_point, hit_normal)) = hit {
                 let mut hit_player = false;
 
-                if let Ok((_, mut health, mut movement, mut collider, mut player_transform)) =
+                if let Ok((client, mut health, mut movement, mut collider, mut player_transform)) =
                     players.get_mut(hit_entity)
                 {
-                    health.current = (health.current - projectile.damage).max(0.0);
-
-                    if health.current <= 0.0 {
-                        movement.velocity = Vec3::ZERO;
-                        set_crouched_state(&mut movement, &mut collider, &mut player_transform, false);
+                    if health.current > 0.0 {
+                        let amount = projectile.damage.min(health.current);
+                        health.current -= amount;
+
+                        events.push(GameEvent::PlayerDamaged {
+                            id: client.id,
+                            attacker: projectile.owner_id,
+                            amount,
+                        });
+
+                        if health.current <= 0.0 {
+                            movement.velocity = Vec3::ZERO;
+                            set_crouched_state(
+                                &mut movement,
+                                &mut collider,
+                                &mut player_transform,
+                                false,
+                            );
+                            events.push(GameEvent::PlayerKilled {
+                                id: client.id,
+                                killer: projectile.owner_id,
+                            });
+                        }
                     }
 
                     hit_player = true;
                 }
 
                 if !hit_player {
-                    if world_state.impact_marks.len() == MAX_IMPACT_MARKS {
-                        world_state.impact_marks.remove(0);
+                    if world_state.impact_marks.len() == MAX_IMPACT_MARKS
+                        && let Some(oldest) = world_state.impact_marks.pop_front()
+                    {
+                        commands.entity(oldest).despawn();
                     }
 
-                    let mark_id = world_state.next_mark_id;
-                    world_state.next_mark_id = world_state.next_mark_id.wrapping_add(1);
-
-                    world_state.impact_marks.push(ImpactMarkData {
-                        id: mark_id,
-                        pos: hit.point.into(),
-                        normal: hit.normal.into(),
-                    });
+                    let mark = commands
+                        .spawn((
+                            Replicate,
+                            ImpactMark {
+                                pos: hit_point.into(),
+                                normal: hit_normal.into(),
+                            },
+                            Transform::from_translation(hit_point),
+                        ))
+                        .id();
+
+                    world_state.impact_marks.push_back(mark);
                 }
 
                 commands.entity(entity).despawn();
@@ -404,39 +482,51 @@ fn projectiles_tick(
         transform.translation += displacement;
         projectile.lifetime -= delta;
 
-        if projectile.lifetime <= 0.0 {
+        // Snapshots can't place it out there anyway.
+        if projectile.lifetime <= 0.0 || !map::in_bounds(transform.translation) {
             commands.entity(entity).despawn();
         }
     }
 }
 
-fn sync_ground_state(mut query: Query<(&mut MovementState, &KinematicCharacterControllerOutput)>) {
-    for (mut movement, output) in query.iter_mut() {
-        movement.grounded = output.grounded;
-
-        if output.grounded && movement.velocity.y < 0.0 {
-            movement.velocity.y = 0.0;
-        }
-    }
+fn snapshot_due(world_state: Res<WorldState>, config: Res<ServerConfig>) -> bool {
+    world_state.tick.is_multiple_of(config.snapshot_interval())
 }
 
+/// Sends every player the world around it, delta encoded against the snapshot it acknowledged.
+///
+/// Enemies behind walls are left out, so a modified client has nothing to reveal.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn send_world_snapshot(
     mut server: ResMut<RenetServer>,
+    mut line_of_sight: ResMut<LineOfSight>,
     world_state: Res<WorldState>,
-    players: Query<(&Transform, &Client, &MovementState, &Health, &Arsenal)>,
+    config: Res<ServerConfig>,
+    rapier_context: ReadRapierContext,
+    time: Res<Time>,
+    mut viewers: Query<(
+        &Client,
+        &ClientInput,
+        &Transform,
+        &Arsenal,
+        &InputBuffer,
+        &Compression,
+        &mut SentSnapshots,
+        &mut SnapshotPacing,
+    )>,
+    players: Query<(&Transform, &Client, &MovementState)>,
     projectiles: Query<(&Projectile, &Transform)>,
 ) {
-    let players = players
+    let player_data = players
         .iter()
-        .map(|(transform, client, movement, health, arsenal)| ClientData {
+        .map(|(transform, client, movement)| ClientData {
             id: client.id,
             pos: transform.translation.into(),
+            vel: movement.velocity.into(),
             rot: transform.rotation.into(),
+            grounded: movement.grounded,
+            jump_queued: movement.jump_queued,
             crouched: movement.crouched,
-            alive: health.current > 0.0,
-            health: health.current,
-            weapon: arsenal.active_weapon,
-            ammo_in_mag: *ammo_for_weapon(arsenal, arsenal.active_weapon),
         })
         .collect();
 
@@ -449,172 +539,342 @@ fn send_world_snapshot(
         })
         .collect();
 
-    let snapshot = WorldSnapshot {
-        players,
+    let world = WorldSnapshot {
+        tick: world_state.tick,
+        players: player_data,
         projectiles,
-        impact_marks: world_state.impact_marks.clone(),
-        fired_projectiles: world_state.fired_projectiles.clone(),
+        private: None,
     };
+    let index = WorldIndex::new(&world, config.interest_radius);
 
-    let sync_message = data::encode(&snapshot);
+    let rapier_context = rapier_context
+        .single()
+        .expect("Default Rapier context to exist");
+    let is_not_player = |entity| !players.contains(entity);
+    let filter = QueryFilter::new()
+        .exclude_sensors()
+        .predicate(&is_not_player);
+    let now = time.elapsed_secs();
 
-    server.broadcast_message(DefaultChannel::Unreliable, sync_message);
+    for (client, input, transform, arsenal, buffer, compression, mut sent, mut pacing) in
+        viewers.iter_mut()
+    {
+        if !pacing.due(world_state.tick, &config) {
+            continue;
+        }
+
+        let may_see = |target| line_of_sight.visible(&rapier_context, filter, client.id, target);
+
+        let baseline = sent.0.get(input.snapshot_ack);
+        let mut snapshot = index.relevant_to(
+            client.id,
+            transform.translation,
+            config.interest_radius,
+            baseline,
+            may_see,
+        );
+        snapshot.private = Some(private_player_data(arsenal, buffer, input, now));
+        let message = data::encode_with(
+            &Envelope::Snapshot(SnapshotDelta::encode(baseline, &snapshot)),
+            *compression,
+        );
+
+        server.send_message(client.id, Channel::Snapshot, message);
+        sent.0.push(snapshot);
+        pacing.sent(world_state.tick);
+    }
 }
 
-fn recv_players_input(
-    mut commands: Commands,
-    mut server: ResMut<RenetServer>,
-    lobby: Res<Lobby>,
+fn private_player_data(
+    arsenal: &Arsenal,
+    buffer: &InputBuffer,
+    input: &ClientInput,
+    now: f32,
+) -> PrivatePlayerData {
+    let seconds_per_shot = arsenal.active_weapon.spec().seconds_per_shot();
+
+    PrivatePlayerData {
+        reload: arsenal.reload_weapon.map(|weapon| ReloadProgress {
+            weapon,
+            remaining: arsenal.reload_timer,
+        }),
+        fire_cooldown: (seconds_per_shot - (now - arsenal.last_shot_at)).max(0.0),
+        buffered_inputs: buffer.len().min(u8::MAX.into()) as u8,
+        last_input_sequence: input.sequence,
+    }
+}
+
+/// Brings the replicated player components in line with the tick, leaving the ones that did not
+/// change untouched so they are not sent again.
+fn update_player_state(
+    mut players: Query<(
+        &MovementState,
+        &Health,
+        &Arsenal,
+        &mut PlayerVisualState,
+        &mut PlayerStatus,
+    )>,
 ) {
-    for client_id in server.clients_id() {
-        while let Some(message) = server.receive_message(client_id, DefaultChannel::ReliableOrdered)
-        {
-            let player_input: ClientInput = data::decode(&message);
+    for (movement, health, arsenal, mut visual_state, mut status) in players.iter_mut() {
+        visual_state.set_if_neq(player_visual_state(movement, health, arsenal));
+        status.set_if_neq(player_status(health, arsenal));
+    }
+}
 
-            if let Some(player_entity) = lobby.players.get(&client_id) {
-                commands.entity(*player_entity).insert(player_input);
-            }
-        }
+fn player_visual_state(
+    movement: &MovementState,
+    health: &Health,
+    arsenal: &Arsenal,
+) -> PlayerVisualState {
+    PlayerVisualState {
+        alive: health.current > 0.0,
+        crouched: movement.crouched,
+        weapon: arsenal.active_weapon,
     }
 }
 
-fn recv_connectivity(
-    mut server_events: MessageReader<ServerEvent>,
+fn player_status(health: &Health, arsenal: &Arsenal) -> PlayerStatus {
+    PlayerStatus {
+        health: health.current,
+        magazines: arsenal.magazines,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn recv_players_input(
     mut commands: Commands,
-    mut lobby: ResMut<Lobby>,
     mut server: ResMut<RenetServer>,
+    mut violations: ResMut<ProtocolViolations>,
+    mut handshakes: ResMut<PendingHandshakes>,
+    mut lobby: ResMut<Lobby>,
+    mut buffers: Query<&mut InputBuffer>,
+    mut budgets: ResMut<MessageBudgets>,
+    config: Res<ServerConfig>,
+    world_state: Res<WorldState>,
 ) {
-    for event in server_events.read() {
-        match event {
-            ServerEvent::ClientConnected { client_id } => {
-                info!("Player {} connected.", client_id);
-
-                let player_entity = commands
-                    .spawn(Client { id: *client_id })
-                    .insert(ClientInput::default())
-                    .insert(Health {
-                        current: PLAYER_MAX_HEALTH,
-                    })
-                    .insert(Arsenal::default())
-                    .insert(Collider::capsule_y(
-                        PLAYER_COLLIDER_HALF_HEIGHT,
-                        PLAYER_COLLIDER_RADIUS,
-                    ))
-                    .insert(KinematicCharacterController {
-                        autostep: Some(CharacterAutostep {
-                            max_height: CharacterLength::Absolute(PLAYER_STEP_HEIGHT),
-                            min_width: CharacterLength::Absolute(PLAYER_COLLIDER_RADIUS * 2.0),
-                            include_dynamic_bodies: false,
-                        }),
-                        snap_to_ground: Some(CharacterLength::Absolute(0.2)),
-                        ..Default::default()
-                    })
-                    .insert(MovementState {
-                        grounded: true,
-                        ..Default::default()
-                    })
-                    .insert(Transform::from_xyz(0.0, PLAYER_RESPAWN_HEIGHT, 0.0))
-                    .id();
-
-                for &player_id in lobby.players.keys() {
-                    let message = data::encode(&ServerMessage::ClientConnected { id: player_id });
-                    server.send_message(*client_id, DefaultChannel::ReliableOrdered, message);
-                }
-
-                lobby.players.insert(*client_id, player_entity);
-
-                let message = data::encode(&ServerMessage::ClientConnected { id: *client_id });
+    // Floods are reported once a second, repeat offenders get kicked.
+    let report_interval = config.tick_rate.round() as u32;
 
-                server.broadcast_message(DefaultChannel::ReliableOrdered, message);
-            }
-            ServerEvent::ClientDisconnected { client_id, reason } => {
-                info!("Player {} disconnected: {}", client_id, reason);
-
-                if let Some(player_entity) = lobby.players.remove(client_id) {
-                    commands.entity(player_entity).despawn();
+    for client_id in server.clients_id() {
+        for channel in [Channel::GameEvent, Channel::Input] {
+            while let Some(message) = server.receive_message(client_id, channel) {
+                if violations.is_kicked(client_id) {
+                    continue;
                 }
 
-                let message = data::encode(&ServerMessage::ClientDisconnected { id: *client_id });
+                if let Charge::Dropped { report } =
+                    budgets.charge(client_id, world_state.tick, report_interval)
+                {
+                    if report {
+                        violations.report(client_id, "message budget exceeded");
+                    }
+                    continue;
+                }
 
-                server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                let envelope = match data::decode(&message) {
+                    Ok(envelope) => envelope,
+                    Err(DecodeError::VersionMismatch { found }) => {
+                        violations.kick(
+                            client_id,
+                            format!(
+                                "Incompatible protocol version: client speaks {}, server speaks {}",
+                                found, PROTOCOL_VERSION
+                            ),
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        violations.report(client_id, e);
+                        continue;
+                    }
+                };
+
+                match envelope {
+                    Envelope::Hello(hello) if handshakes.0.remove(&client_id).is_some() => {
+                        if !builds_compatible(&hello.build_hash, BUILD_HASH) {
+                            violations.kick(
+                                client_id,
+                                format!(
+                                    "Incompatible build: client is {}, server is {}",
+                                    hello.build_hash, BUILD_HASH
+                                ),
+                            );
+                            continue;
+                        }
+
+                        let compression = if config.compression {
+                            hello.compression
+                        } else {
+                            Compression::None
+                        };
+
+                        join_lobby(
+                            &mut commands,
+                            &mut server,
+                            &mut lobby,
+                            &config,
+                            client_id,
+                            compression,
+                        );
+                    }
+                    Envelope::Input(mut inputs) => {
+                        let Some(player_entity) = lobby.players.get(&client_id) else {
+                            violations.report(client_id, "input before handshake");
+                            continue;
+                        };
+
+                        if inputs.is_empty() || inputs.len() > INPUT_REDUNDANCY {
+                            violations.report(client_id, "input packet of unexpected size");
+                            continue;
+                        }
+
+                        // One report per packet, every input is repeated in the following ones.
+                        let verdict = inputs.iter_mut().map(check_input).max();
+
+                        match verdict.unwrap_or(InputCheck::Valid) {
+                            InputCheck::Valid | InputCheck::Corrected => {}
+                            InputCheck::Clamped(reason) => violations.report(client_id, reason),
+                            InputCheck::Rejected(reason) => {
+                                violations.report(client_id, reason);
+                                continue;
+                            }
+                        }
+
+                        // Freshly joined players get their buffer once the spawn commands apply.
+                        if let Ok(mut buffer) = buffers.get_mut(*player_entity) {
+                            buffer.extend(inputs);
+                        }
+                    }
+                    _ => violations.report(client_id, "unexpected message"),
+                }
             }
         }
     }
 }
 
-fn set_crouched_state(
-    movement: &mut MovementState,
-    collider: &mut Collider,
-    transform: &mut Transform,
-    crouched: bool,
+fn builds_compatible(client: &str, server: &str) -> bool {
+    // Builds outside of a git checkout can't be told apart, let them through.
+    client == server || client == "unknown" || server == "unknown"
+}
+
+fn expire_handshakes(
+    time: Res<Time>,
+    mut handshakes: ResMut<PendingHandshakes>,
+    mut violations: ResMut<ProtocolViolations>,
 ) {
-    if movement.crouched == crouched {
-        return;
-    }
+    let now = time.elapsed_secs();
 
-    movement.crouched = crouched;
-    *collider = Collider::capsule_y(
-        current_collider_half_height(crouched),
-        PLAYER_COLLIDER_RADIUS,
-    );
-    transform.translation.y += if crouched {
-        crouched_eye_height() - standing_eye_height()
-    } else {
-        standing_eye_height() - crouched_eye_height()
-    };
+    handshakes.0.retain(|&client_id, connected_at| {
+        let expired = now - *connected_at > HANDSHAKE_TIMEOUT;
+
+        if expired {
+            violations.kick(client_id, "Handshake timed out");
+        }
+
+        !expired
+    });
 }
 
-fn can_stand_up(entity: Entity, rapier_context: &RapierContext<'_>, translation: Vec3) -> bool {
-    let standing_shape = Collider::capsule_y(PLAYER_COLLIDER_HALF_HEIGHT, PLAYER_COLLIDER_RADIUS);
-    let shape_position = translation + Vec3::Y * (standing_eye_height() - crouched_eye_height());
-    let filter = QueryFilter::new()
-        .exclude_collider(entity)
-        .exclude_sensors();
-
-    rapier_context
-        .cast_shape(
-            shape_position,
-            Quat::IDENTITY,
-            Vec3::ZERO,
-            (&standing_shape).into(),
-            ShapeCastOptions {
-                max_time_of_impact: 0.0,
-                stop_at_penetration: true,
-                compute_impact_geometry_on_penetration: false,
-                target_distance: 0.0,
-            },
-            filter,
-        )
-        .is_none()
+fn join_lobby(
+    commands: &mut Commands,
+    server: &mut RenetServer,
+    lobby: &mut Lobby,
+    config: &ServerConfig,
+    client_id: ClientId,
+    compression: Compression,
+) {
+    info!("Player {} joined.", client_id);
+
+    let movement = MovementState {
+        grounded: true,
+        ..Default::default()
+    };
+    let health = Health {
+        current: PLAYER_MAX_HEALTH,
+    };
+    let arsenal = Arsenal::default();
+
+    let player_entity = commands
+        .spawn(Client { id: client_id })
+        .insert(Replicate)
+        .insert(NetworkOwner(client_id))
+        .insert(player_visual_state(&movement, &health, &arsenal))
+        .insert(player_status(&health, &arsenal))
+        .insert(ClientInput::default())
+        .insert(InputBuffer::default())
+        .insert(TurnLimit::default())
+        .insert(SentSnapshots::default())
+        .insert(SnapshotPacing::default())
+        .insert(compression)
+        .insert(health)
+        .insert(arsenal)
+        .insert(player_collider(false))
+        .insert(player_collision_groups())
+        .insert(movement)
+        .insert(Transform::from_xyz(0.0, PLAYER_RESPAWN_HEIGHT, 0.0))
+        .id();
+
+    let welcome = data::encode(&Envelope::Server(ServerMessage::Welcome {
+        tick_rate: config.tick_rate,
+        map: config.map.clone(),
+    }));
+    server.send_message(client_id, Channel::GameEvent, welcome);
+
+    // Other players learn about it once it is replicated.
+    lobby.players.insert(client_id, player_entity);
 }
 
-fn current_collider_half_height(crouched: bool) -> f32 {
-    if crouched {
-        PLAYER_CROUCH_COLLIDER_HALF_HEIGHT
-    } else {
-        PLAYER_COLLIDER_HALF_HEIGHT
+/// A transport error only concerns the client it came from, the others keep playing.
+fn log_transport_errors(mut errors: MessageReader<NetcodeTransportError>) {
+    for error in errors.read() {
+        error!("Transport error: {}", error);
     }
 }
 
-fn standing_eye_height() -> f32 {
-    PLAYER_COLLIDER_HALF_HEIGHT + PLAYER_COLLIDER_RADIUS
-}
+fn recv_connectivity(
+    mut server_events: MessageReader<ServerEvent>,
+    mut commands: Commands,
+    mut lobby: ResMut<Lobby>,
+    mut handshakes: ResMut<PendingHandshakes>,
+    mut budgets: ResMut<MessageBudgets>,
+    transport: Res<NetcodeServerTransport>,
+    time: Res<Time>,
+) {
+    for event in server_events.read() {
+        match event {
+            ServerEvent::ClientConnected { client_id } => {
+                // Only secure connect tokens carry a name.
+                let name = transport
+                    .user_data(*client_id)
+                    .and_then(|user_data| auth::decode_player_name(&user_data))
+                    .unwrap_or_else(|| "anonymous".to_owned());
+
+                info!(
+                    "Player {} ({}) connected, awaiting handshake.",
+                    client_id, name
+                );
+
+                handshakes.0.insert(*client_id, time.elapsed_secs());
+            }
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                info!("Player {} disconnected: {}", client_id, reason);
 
-fn crouched_eye_height() -> f32 {
-    PLAYER_CROUCH_COLLIDER_HALF_HEIGHT + PLAYER_COLLIDER_RADIUS
-}
+                handshakes.0.remove(client_id);
+                budgets.forget(*client_id);
 
-fn weapon_index(weapon: WeaponKind) -> usize {
-    match weapon {
-        WeaponKind::Rifle => 0,
-        WeaponKind::Pistol => 1,
+                // Despawned on every client by replication.
+                if let Some(player_entity) = lobby.players.remove(client_id) {
+                    commands.entity(player_entity).despawn();
+                }
+            }
+        }
     }
 }
 
 fn ammo_for_weapon(arsenal: &Arsenal, weapon: WeaponKind) -> &u32 {
-    &arsenal.magazines[weapon_index(weapon)]
+    &arsenal.magazines[weapon.index()]
 }
 
 fn ammo_for_weapon_mut(arsenal: &mut Arsenal, weapon: WeaponKind) -> &mut u32 {
-    &mut arsenal.magazines[weapon_index(weapon)]
+    &mut arsenal.magazines[weapon.index()]
 }
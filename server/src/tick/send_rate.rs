@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+use bevy_renet2::prelude::RenetServer;
+use common::Client;
+
+use crate::config::ServerConfig;
+
+/// Loss above which a client is sent fewer snapshots.
+const HIGH_LOSS: f64 = 0.05;
+/// Loss below which a client may be sent more snapshots again.
+const LOW_LOSS: f64 = 0.01;
+/// Share of the bandwidth limit a client has to stay under before its rate goes back up, so it
+/// does not bounce off the limit.
+const BANDWIDTH_HEADROOM: f64 = 0.75;
+/// Seconds between two adjustments, about the window renet averages loss and bandwidth over.
+const ADJUST_INTERVAL: f32 = 1.0;
+
+/// How often one client is sent snapshots, in multiples of the configured snapshot interval.
+///
+/// Only snapshots are paced, game events still go out on the tick they happen.
+#[derive(Debug, Component)]
+pub struct SnapshotPacing {
+    /// 1 sends every snapshot, 2 every other one and so on.
+    multiplier: u32,
+    sent_at: Option<u32>,
+    adjusted_at: f32,
+}
+
+impl Default for SnapshotPacing {
+    fn default() -> Self {
+        Self {
+            multiplier: 1,
+            sent_at: None,
+            adjusted_at: 0.0,
+        }
+    }
+}
+
+impl SnapshotPacing {
+    pub fn due(&self, tick: u32, config: &ServerConfig) -> bool {
+        self.sent_at.is_none_or(|sent_at| {
+            tick.wrapping_sub(sent_at) >= config.snapshot_interval() * self.multiplier
+        })
+    }
+
+    pub fn sent(&mut self, tick: u32) {
+        self.sent_at = Some(tick);
+    }
+}
+
+/// Sends fewer snapshots to clients losing packets or going over their bandwidth limit, and
+/// more again once their link clears up.
+pub fn adapt_snapshot_rates(
+    time: Res<Time>,
+    server: Res<RenetServer>,
+    config: Res<ServerConfig>,
+    mut players: Query<(&Client, &mut SnapshotPacing)>,
+) {
+    let now = time.elapsed_secs();
+    let limit = config.client_bandwidth_limit * 1000.0;
+
+    for (client, mut pacing) in players.iter_mut() {
+        if now - pacing.adjusted_at < ADJUST_INTERVAL {
+            continue;
+        }
+
+        let Ok(info) = server.network_info(client.id) else {
+            continue;
+        };
+
+        pacing.adjusted_at = now;
+
+        let multiplier = if info.packet_loss > HIGH_LOSS || info.bytes_sent_per_second > limit {
+            pacing.multiplier + 1
+        } else if info.packet_loss < LOW_LOSS
+            && info.bytes_sent_per_second < limit * BANDWIDTH_HEADROOM
+        {
+            pacing.multiplier - 1
+        } else {
+            pacing.multiplier
+        };
+
+        let multiplier = multiplier.clamp(1, config.max_snapshot_multiplier());
+
+        if multiplier != pacing.multiplier {
+            debug!(
+                "Sending snapshots to {} at {:.0} Hz ({:.1}% loss, {:.1} KB/s).",
+                client.id,
+                config.snapshot_rate / multiplier as f64,
+                info.packet_loss * 100.0,
+                info.bytes_sent_per_second / 1000.0
+            );
+            pacing.multiplier = multiplier;
+        }
+    }
+}
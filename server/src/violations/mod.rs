@@ -0,0 +1,121 @@
+use std::{fmt::Display, time::Duration};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_renet2::prelude::{ClientId, RenetServer, ServerEvent};
+use common::{
+    Envelope, ServerMessage,
+    data::{self, Channel},
+};
+
+pub struct Plugin;
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ProtocolViolations>().add_systems(
+            Update,
+            (forget_disconnected, forgive_violations, kick_offenders).chain(),
+        );
+    }
+}
+
+/// Outstanding violations that get a client kicked.
+const MAX_VIOLATIONS: f32 = 8.0;
+/// Violations forgiven per second, only clients offending faster than this for a while get
+/// kicked, not ones slipping up now and then over a long session.
+const FORGIVEN_PER_SECOND: f32 = 0.1;
+/// Time given to the kick message to reach the client before the connection is dropped.
+const KICK_GRACE: Duration = Duration::from_millis(250);
+
+/// Per-client record of protocol violations and pending kicks.
+#[derive(Debug, Default, Resource)]
+pub struct ProtocolViolations {
+    clients: HashMap<ClientId, ClientViolations>,
+}
+
+#[derive(Debug, Default)]
+struct ClientViolations {
+    /// Violations reported minus those forgiven since.
+    outstanding: f32,
+    kick_reason: Option<String>,
+    kicked_at: Option<Duration>,
+}
+
+impl ProtocolViolations {
+    pub fn report(&mut self, client_id: ClientId, violation: impl Display) {
+        let client = self.clients.entry(client_id).or_default();
+        client.outstanding += 1.0;
+
+        warn!(
+            "Protocol violation {:.1}/{} by client {}: {}",
+            client.outstanding, MAX_VIOLATIONS, client_id, violation
+        );
+
+        if client.outstanding >= MAX_VIOLATIONS && client.kick_reason.is_none() {
+            client.kick_reason = Some(format!("Too many protocol violations ({violation})"));
+        }
+    }
+
+    pub fn kick(&mut self, client_id: ClientId, reason: impl Into<String>) {
+        let client = self.clients.entry(client_id).or_default();
+
+        if client.kick_reason.is_none() {
+            client.kick_reason = Some(reason.into());
+        }
+    }
+
+    /// Kicked clients are about to be dropped, their messages should be ignored.
+    pub fn is_kicked(&self, client_id: ClientId) -> bool {
+        self.clients
+            .get(&client_id)
+            .is_some_and(|client| client.kick_reason.is_some())
+    }
+}
+
+fn forget_disconnected(
+    mut server_events: MessageReader<ServerEvent>,
+    mut violations: ResMut<ProtocolViolations>,
+) {
+    for event in server_events.read() {
+        if let ServerEvent::ClientDisconnected { client_id, .. } = event {
+            violations.clients.remove(client_id);
+        }
+    }
+}
+
+fn forgive_violations(time: Res<Time>, mut violations: ResMut<ProtocolViolations>) {
+    let forgiven = FORGIVEN_PER_SECOND * time.delta_secs();
+
+    for client in violations.clients.values_mut() {
+        client.outstanding = (client.outstanding - forgiven).max(0.0);
+    }
+}
+
+fn kick_offenders(
+    time: Res<Time>,
+    mut server: ResMut<RenetServer>,
+    mut violations: ResMut<ProtocolViolations>,
+) {
+    let now = time.elapsed();
+
+    for (&client_id, client) in violations.clients.iter_mut() {
+        let Some(reason) = &client.kick_reason else {
+            continue;
+        };
+
+        match client.kicked_at {
+            None => {
+                info!("Kicking client {}: {}", client_id, reason);
+
+                let message = data::encode(&Envelope::Server(ServerMessage::Kicked {
+                    reason: reason.clone(),
+                }));
+                server.send_message(client_id, Channel::GameEvent, message);
+                client.kicked_at = Some(now);
+            }
+            Some(kicked_at) if now - kicked_at >= KICK_GRACE => {
+                server.disconnect(client_id);
+            }
+            Some(_) => {}
+        }
+    }
+}